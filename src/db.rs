@@ -1,5 +1,3 @@
-use rusqlite::{SqliteConnection, SqliteError};
-use rusqlite::types::{ToSql, FromSql};
 use rustc_serialize::json::{Json, ToJson, ParserError};
 use jmap::parse::{FromJson, ParseError};
 use jmap::parse::Presence::Present;
@@ -9,57 +7,16 @@ use jmap::*;
 use std::collections::BTreeMap;
 use std::error::Error;
 use std::convert::From;
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
 use std::fmt;
 use std::cmp;
 use self::DbError::*;
 
-use std::path::Path;
-
-const VERSION: u32 = 1;
-
-const CREATE_SQL: [&'static str; 6] = [
-r###"
-CREATE TABLE records (
-    rowid       INTEGER PRIMARY KEY,
-    id          TEXT NOT NULL,
-    userid      INTEGER NOT NULL,
-    type        INTEGER NOT NULL,
-    modseq      INTEGER NOT NULL,
-    deleted     INTEGER NOT NULL DEFAULT 0,
-    json        TEXT NOT NULL,
-    UNIQUE( id, userid )
-);
-"###,
-r###"
-CREATE INDEX idx_record_id_userid          ON records ( id, userid );
-"###,
-r###"
-CREATE INDEX idx_record_userid_type        ON records ( userid, type );
-"###,
-r###"
-CREATE INDEX idx_record_userid_type_modseq ON records ( userid, type, modseq );
-"###,
-r###"
-CREATE TABLE modseq (
-    userid      INTEGER NOT NULL,
-    type        INTEGER NOT NULL,
-    modseq      INTEGER NOT NULL,
-    low_modseq  INTEGER NOT NULL,
-    UNIQUE( userid, type )
-);
-"###,
-r###"
-CREATE INDEX idx_userid_type ON modseq ( userid, type );
-"###,
-];
+use notify;
+use notify::StateChange;
 
-/*
-const UPGRADE_SQL: [&'static str; 1] = [
-    // v1
-    ""
-];
-*/
+use storage::Storage;
+use sqlite::{SqliteStorage, DbLocation};
 
 #[derive(Clone, PartialEq, Debug)]
 pub enum DbError {
@@ -91,12 +48,6 @@ impl fmt::Display for DbError {
     }
 }
 
-impl From<SqliteError> for DbError {
-    fn from(e: SqliteError) -> DbError {
-        InternalError(format!("sqlite: {}", e))
-    }
-}
-
 impl From<ParserError> for DbError {
     fn from(e: ParserError) -> DbError {
         InternalError(format!("json: {}", e))
@@ -121,69 +72,172 @@ impl From<DbError> for MethodError {
 }
 
 
+// build a SetError for notCreated/notUpdated/notDestroyed
+fn set_error(typ: &str, description: String) -> Result<SetError,DbError> {
+    let mut o = BTreeMap::new();
+    o.insert("type".to_string(), Json::String(typ.to_string()));
+    o.insert("description".to_string(), Json::String(description));
+    Ok(try!(SetError::from_json(&Json::Object(o))))
+}
+
 pub trait RecordType {
     fn record_type() -> i32;
+    fn type_name() -> &'static str;
+
+    // called before a created or updated record is stored, with the stored
+    // json if it's an update. returning a SetError rejects just this record
+    fn check_record(_db: &Db, _userid: i64, _old: Option<&Json>, _new: &Json) -> Result<Option<SetError>,DbError> {
+        Ok(None)
+    }
+
+    // called after records are updated, in the same transaction, with the
+    // old and new json of each
+    fn records_updated(_db: &Db, _userid: i64, _changes: &Vec<(Json,Json)>) -> Result<(),DbError> {
+        Ok(())
+    }
+
+    // called after records are destroyed, in the same transaction
+    fn records_destroyed(_db: &Db, _userid: i64, _ids: &Vec<String>) -> Result<(),DbError> {
+        Ok(())
+    }
 }
 impl RecordType for Contact {
     fn record_type() -> i32 { 1 }
+    fn type_name() -> &'static str { "Contact" }
+
+    fn records_destroyed(db: &Db, userid: i64, ids: &Vec<String>) -> Result<(),DbError> {
+        db.remove_group_contacts(userid, ids)
+    }
 }
 impl RecordType for ContactGroup {
     fn record_type() -> i32 { 2 }
+    fn type_name() -> &'static str { "ContactGroup" }
+
+    fn check_record(db: &Db, userid: i64, old: Option<&Json>, new: &Json) -> Result<Option<SetError>,DbError> {
+        db.check_group_contacts(userid, old, new)
+    }
 }
 impl RecordType for Calendar {
     fn record_type() -> i32 { 3 }
+    fn type_name() -> &'static str { "Calendar" }
 }
 impl RecordType for CalendarEvent {
     fn record_type() -> i32 { 4 }
+    fn type_name() -> &'static str { "CalendarEvent" }
 }
 impl RecordType for Mailbox {
     fn record_type() -> i32 { 5 }
+    fn type_name() -> &'static str { "Mailbox" }
 }
 impl RecordType for Message {
     fn record_type() -> i32 { 6 }
+    fn type_name() -> &'static str { "Message" }
+
+    fn check_record(_db: &Db, _userid: i64, old: Option<&Json>, new: &Json) -> Result<Option<SetError>,DbError> {
+        let old = match old {
+            Some(o) => o,
+            None    => return Ok(None),
+        };
+        match changed_properties(old, new).into_iter().find(|p| !MESSAGE_MUTABLE_PROPERTIES.contains(&&p[..])) {
+            Some(p) => Ok(Some(try!(set_error("invalidProperties", format!("{}: cannot be changed", p))))),
+            None    => Ok(None),
+        }
+    }
+
+    fn records_updated(db: &Db, userid: i64, changes: &Vec<(Json,Json)>) -> Result<(),DbError> {
+        db.touch_message_mailboxes(userid, changes)
+    }
+}
+
+// the only parts of a message that can change once it exists. moving a
+// message between mailboxes is a change to mailboxIds
+const MESSAGE_MUTABLE_PROPERTIES: [&'static str; 6] = [
+    "mailboxIds", "isUnread", "isFlagged", "isAnswered", "isDraft", "keywords",
+];
+
+// names of top-level properties that differ between two json objects
+fn changed_properties(old: &Json, new: &Json) -> Vec<String> {
+    let (o, n) = match (old.as_object(), new.as_object()) {
+        (Some(o), Some(n)) => (o, n),
+        _                  => return Vec::new(),
+    };
+    o.keys().chain(n.keys().filter(|k| !o.contains_key(*k)))
+        .filter(|k| o.get(*k) != n.get(*k))
+        .cloned()
+        .collect()
+}
+
+
+#[derive(Clone, Debug)]
+pub struct PushCallback {
+    pub id:      i64,
+    pub userid:  i64,
+    pub url:     String,
+    pub expires: i64,
+    pub states:  BTreeMap<String,String>,
 }
 
 
 #[derive(Debug)]
 pub struct Db {
-    conn:   SqliteConnection,
-    in_txn: Cell<bool>,
+    store:   Box<Storage>,
+    in_txn:  Cell<bool>,
+
+    // state changes made in the current transaction, published on commit
+    changes: RefCell<Vec<StateChange>>,
 }
 
 impl Db {
-    pub fn open() -> Result<Db,DbError> {
-        //let conn = try!(SqliteConnection::open_in_memory());
-        let conn = try!(SqliteConnection::open(&Path::new("db.sqlite")));
-        let db = Db {
-            conn:   conn,
-            in_txn: Cell::new(false),
-        };
+    pub fn new(store: Box<Storage>) -> Db {
+        Db {
+            store:   store,
+            in_txn:  Cell::new(false),
+            changes: RefCell::new(Vec::new()),
+        }
+    }
 
-        try!(db.upgrade());
+    pub fn open(location: &DbLocation) -> Result<Db,DbError> {
+        Ok(Db::new(Box::new(try!(SqliteStorage::open(location)))))
+    }
 
-        Ok(db)
+    // true if a transaction is open. it's only left open if rolling back
+    // failed, and then the connection shouldn't be used again
+    pub fn in_transaction(&self) -> bool {
+        self.in_txn.get()
     }
 
     fn do_transaction<F,T>(&self, f: F, nested: bool) -> Result<T,DbError> where F: Fn() -> Result<T,DbError> {
+        let mark = self.changes.borrow().len();
+
         let r = f();
 
         match r {
             Ok(_) => match nested {
                 false => {
-                    try!(self.exec("COMMIT", &[]));
+                    let changes: Vec<StateChange> = self.changes.borrow_mut().drain(..).collect();
+                    // a failed commit (eg busy) leaves the transaction open;
+                    // roll it back so the connection is usable again
+                    if let Err(e) = self.store.commit() {
+                        try!(self.store.rollback());
+                        self.in_txn.set(false);
+                        return Err(e);
+                    }
                     self.in_txn.set(false);
+                    notify::publish(changes);
                 },
                 true => {
-                    try!(self.exec("RELEASE sp", &[]));
+                    try!(self.store.release_savepoint());
                 },
             },
             Err(_) => match nested {
                 false => {
-                    try!(self.exec("ROLLBACK", &[]));
+                    self.changes.borrow_mut().clear();
+                    try!(self.store.rollback());
                     self.in_txn.set(false);
                 },
                 true => {
-                    try!(self.exec("ROLLBACK TO sp", &[]));
+                    self.changes.borrow_mut().truncate(mark);
+                    try!(self.store.rollback_savepoint());
                 },
             },
         };
@@ -192,7 +246,7 @@ impl Db {
     }
 
     pub fn exclusive<F,T>(&self, f: F) -> Result<T,DbError> where F: Fn() -> Result<T,DbError> {
-        try!(self.exec("BEGIN EXCLUSIVE", &[]));
+        try!(self.store.begin_exclusive());
         self.in_txn.set(true);
         self.do_transaction(f, false)
     }
@@ -200,91 +254,56 @@ impl Db {
     pub fn transaction<F,T>(&self, f: F) -> Result<T,DbError> where F: Fn() -> Result<T,DbError> {
         let nested = match self.in_txn.get() {
             false => {
-                try!(self.exec("BEGIN DEFERRED", &[]));
+                try!(self.store.begin());
                 self.in_txn.set(true);
                 false
             },
             true  => {
-                try!(self.exec("SAVEPOINT sp", &[]));
+                try!(self.store.savepoint());
                 true
             },
         };
         self.do_transaction(f, nested)
     }
 
-    fn exec(&self, sql: &str, params: &[&ToSql]) -> Result<usize,DbError> {
-        let mut stmt = try!(self.conn.prepare(sql));
-        Ok(try!(stmt.execute(params)) as usize)
-    }
-
-    fn exec_value<T>(&self, sql: &str, params: &[&ToSql]) -> Result<Option<T>,DbError> where T: FromSql {
-        let mut stmt = try!(self.conn.prepare(sql));
-        let mut res = try!(stmt.query(params));
+    // check a client state against the oldest state we can calculate changes from
+    fn check_since_state(&self, userid: i64, rectype: i32, since_state: &String) -> Result<i64,DbError> {
+        let parsed = since_state.parse::<i64>();
+        let modseq = match parsed {
+            Err(_) => 0,
+            Ok(i)  => cmp::max(i,0),
+        };
 
-        match res.next() {
-            None       => Ok(None),
-            Some(next) =>
-                match next {
-                    Err(e)   => Err(InternalError(format!("sqlite: {}", e))),
-                    Ok(next) => {
-                        let v: T = next.get(0);
-                        Ok(Some(v))
-                    },
-            }
+        let valid = match try!(self.store.get_low_modseq(userid, rectype)) {
+            None    => false,
+            Some(v) => v <= modseq,
+        };
+        match valid {
+            true  => Ok(modseq),
+            false => Err(StateTooOld),
         }
     }
 
-    fn version(&self) -> Result<u32,DbError> {
-        let v = try!(self.exec_value::<i32>("PRAGMA user_version", &[]));
-        match v {
-            Some(v) => Ok(v as u32),
-            None    => Ok(0),
+    pub fn get_state<R: Record>(&self, userid: i64) -> Result<String,DbError> where R: RecordType {
+        match try!(self.store.get_modseq(userid, R::record_type())) {
+            None    => Ok("0".to_string()),
+            Some(v) => Ok(v.to_string()),
         }
     }
 
-    fn set_version(&self, v: u32) -> Result<(),DbError> {
-        try!(self.exec(format!("PRAGMA user_version = {}", v as i32).as_ref(), &[]));
-        Ok(())
-    }
-
-    fn upgrade(&self) -> Result<(),DbError> {
+    pub fn get_states(&self, userid: i64) -> Result<BTreeMap<String,String>,DbError> {
         self.transaction(|| {
-            let ver = try!(self.version());
-            if ver == VERSION { return Ok(()) }
-
-            // new database
-            if ver == 0 {
-                for sql in CREATE_SQL.iter() {
-                    try!(self.exec(sql, &[]));
-                }
-            }
-
-            /*
-            // existing database, upgrade required
-            else {
-                // XXX 
-            }
-            */
-
-            try!(self.set_version(VERSION));
-
-            info!("upgraded db to version {}", VERSION);
-
-            Ok(())
+            let mut states = BTreeMap::new();
+            states.insert(Contact::type_name().to_string(),       try!(self.get_state::<Contact>(userid)));
+            states.insert(ContactGroup::type_name().to_string(),  try!(self.get_state::<ContactGroup>(userid)));
+            states.insert(Calendar::type_name().to_string(),      try!(self.get_state::<Calendar>(userid)));
+            states.insert(CalendarEvent::type_name().to_string(), try!(self.get_state::<CalendarEvent>(userid)));
+            states.insert(Mailbox::type_name().to_string(),       try!(self.get_state::<Mailbox>(userid)));
+            states.insert(Message::type_name().to_string(),       try!(self.get_state::<Message>(userid)));
+            Ok(states)
         })
     }
 
-    pub fn get_state<R: Record>(&self, userid: i64) -> Result<String,DbError> where R: RecordType {
-        let rectype = R::record_type();
-
-        let params: Vec<&ToSql> = vec!(&userid, &rectype);
-        let sv = try!(self.exec_value::<i64>("SELECT modseq FROM modseq WHERE userid = ? AND type = ?", params.as_ref()));
-        match sv {
-            None    => Ok("0".to_string()),
-            Some(v) => Ok(v.to_string()),
-        }
-    }
-
     pub fn check_state<R: Record>(&self, userid: i64, state: &String) -> Result<(),DbError> where R: RecordType {
         let s = try!(self.get_state::<R>(userid));
         match s == *state {
@@ -294,15 +313,14 @@ impl Db {
     }
 
     pub fn next_state<R: Record>(&self, userid: i64) -> Result<String,DbError> where R: RecordType {
-        let rectype = R::record_type();
-
-        let params: Vec<&ToSql> = vec!(&userid, &rectype);
-
         self.transaction(|| {
-            if let 0 = try!(self.exec("UPDATE modseq SET modseq = (modseq+1) WHERE userid = ? AND type = ?", &params)) {
-                try!(self.exec("INSERT INTO modseq ( userid, type, modseq, low_modseq ) VALUES ( ?, ?, 1, 1 )", &params));
-            }
-            self.get_state::<R>(userid)
+            let state = try!(self.store.next_modseq(userid, R::record_type())).to_string();
+            self.changes.borrow_mut().push(StateChange {
+                userid:    userid,
+                type_name: R::type_name(),
+                state:     state.clone(),
+            });
+            Ok(state)
         })
     }
 
@@ -310,56 +328,15 @@ impl Db {
         let rectype = R::record_type();
 
         self.transaction(|| {
-            let modseq: i64;
-
-            let mut sql = "SELECT json FROM records WHERE userid = ? AND type = ? AND deleted = 0".to_string();
-            let mut params: Vec<&ToSql> = vec!(&userid, &rectype);
-
-            if let Some(ref since_state) = since_state {
-                let parsed = since_state.parse::<i64>();
-                modseq = match parsed {
-                    Err(_) => 0,
-                    Ok(i)  => cmp::max(i,0),
-                };
-
-                let mv = try!(self.exec_value::<i64>("SELECT low_modseq FROM modseq WHERE userid = ? AND type = ?", params.as_ref()));
-                let valid = match mv {
-                    None    => false,
-                    Some(v) => v <= modseq,
-                };
-                if let false = valid {
-                    return Err(StateTooOld);
-                }
-
-                sql.push_str(" AND modseq > ?");
-                params.push(&modseq);
-            }
-
-            if let Some(ref ids) = ids {
-                sql.push_str(" AND id IN ( ");
-
-                let mut i = ids.iter();
-                if let Some(id) = i.next() {
-                    sql.push_str("?");
-                    params.push(id);
-                }
-                for id in i {
-                    sql.push_str(",?");
-                    params.push(id);
-                }
-
-                sql.push_str(" )");
-            }
-
-            let mut stmt = try!(self.conn.prepare(sql.as_ref()));
-            let res = try!(stmt.query(params.as_ref()));
+            let modseq = match since_state {
+                Some(s) => Some(try!(self.check_since_state(userid, rectype, s))),
+                None    => None,
+            };
 
             let mut records: Vec<R> = Vec::new();
-            for row in res {
-                if let Ok(ref r) = row {
-                    let json = try!(Json::from_str((r.get::<String>(0)).as_ref()));
-                    records.push(try!(R::from_json(&json)));
-                }
+            for (_, j) in try!(self.store.get_records(userid, rectype, ids, modseq)).into_iter() {
+                let json = try!(Json::from_str(j.as_ref()));
+                records.push(try!(R::from_json(&json)));
             }
 
             Ok(records)
@@ -370,65 +347,21 @@ impl Db {
         let rectype = R::record_type();
 
         self.transaction(|| {
-            let parsed = since_state.parse::<i64>();
-            let modseq = match parsed {
-                Err(_) => 0,
-                Ok(i)  => cmp::max(i,0),
-            };
-
-            let max;
-            let max1;
-
-            let mut params: Vec<&ToSql> = vec!(&userid, &rectype);
-
-            let mv = try!(self.exec_value::<i64>("SELECT low_modseq FROM modseq WHERE userid = ? AND type = ?", params.as_ref()));
-            let valid = match mv {
-                None    => false,
-                Some(v) => v <= modseq,
-            };
-            if let false = valid {
-                return Err(StateTooOld);
-            }
-
-            params.push(&modseq);
-
-            let mut sql = " FROM records WHERE userid = ? AND type = ? AND modseq > ?".to_string();
+            let modseq = try!(self.check_since_state(userid, rectype, since_state));
 
             if let Some(max_changes) = max_changes {
-                sql.push_str(" LIMIT ?");
-
-                max1 = max_changes + 1;
-                params.push(&max1);
-
-                let mut s = "SELECT COUNT(*)".to_string();
-                s.push_str(sql.as_ref());
-
-                let count = try!(self.exec_value::<i64>(s.as_ref(), params.as_ref())).unwrap();
-                if count >= max1 {
+                let count = try!(self.store.count_changes(userid, rectype, modseq));
+                if count > max_changes {
                     return Err(TooManyChanges);
                 }
-
-                params.pop();
-                max = max_changes;
-                params.push(&max);
             }
 
-            let mut s = "SELECT id,deleted".to_string();
-            s.push_str(sql.as_ref());
-
-            let mut stmt = try!(self.conn.prepare(s.as_ref()));
-            let res = try!(stmt.query(params.as_ref()));
-
             let mut changed: Vec<String> = Vec::new();
             let mut removed: Vec<String> = Vec::new();
-            for row in res {
-                if let Ok(ref r) = row {
-                    let id = r.get::<String>(0);
-                    let deleted = r.get::<i64>(1) == 1;
-                    match deleted {
-                        true  => removed.push(id),
-                        false => changed.push(id),
-                    }
+            for (id, deleted) in try!(self.store.get_changes(userid, rectype, modseq, max_changes)).into_iter() {
+                match deleted {
+                    true  => removed.push(id),
+                    false => changed.push(id),
                 }
             }
 
@@ -439,27 +372,22 @@ impl Db {
     pub fn create_records<R: Record>(&self, userid: i64, create: &BTreeMap<String,R::Partial>) -> Result<(BTreeMap<String,R::Partial>,BTreeMap<String,SetError>),DbError> where R: RecordType {
         let rectype = R::record_type();
 
-        // XXX spec doesn't list any reasons why a create could fail (SetError)
-        // so for now we'll always return an empty notCreated list
         self.transaction(|| {
-            let mut stmt = try!(self.conn.prepare("INSERT INTO records ( userid, type, modseq, id, json ) VALUES ( ?, ?, (SELECT modseq FROM modseq WHERE userid = ? AND type = ?), ?, ?)"));
-            let params: Vec<&ToSql> = vec!(&userid, &rectype, &userid, &rectype);
-
             // iterative style so we can use try!
             let mut created = BTreeMap::new();
+            let mut not_created: BTreeMap<String,SetError> = BTreeMap::new();
             for (client_id, pr) in create.iter() {
                 // XXX invalidArguments if incoming has an id already
                 let r = R::default().updated_with(&pr);
-                let json = r.to_json().to_string();
-                let id = r.id().clone();
-                let mut p = params.clone();
-                p.push(&id);
-                p.push(&json);
-                try!(stmt.execute(&p));
+                let rj = r.to_json();
+                if let Some(e) = try!(R::check_record(self, userid, None, &rj)) {
+                    not_created.insert(client_id.clone(), e);
+                    continue;
+                }
+                try!(self.store.insert_record(userid, rectype, &r.id(), &rj.to_string()));
                 let cpr = r.to_filtered_partial(&vec!("id".to_string()));
                 created.insert(client_id.clone(), cpr);
             }
-            let not_created: BTreeMap<String,SetError> = BTreeMap::new();
             Ok((created, not_created))
         })
     }
@@ -467,38 +395,35 @@ impl Db {
     pub fn update_records<R: Record>(&self, userid: i64, update: &BTreeMap<String,R::Partial>) -> Result<(Vec<String>,BTreeMap<String,SetError>),DbError> where R: RecordType {
         let rectype = R::record_type();
 
-        // XXX spec doesn't list any reasons why a update could fail (SetError)
-        // so for now we'll always return an empty notUpdated list
         self.transaction(|| {
-            let params: Vec<&ToSql> = vec!(&userid, &rectype);
-
-            let mut update_stmt = try!(self.conn.prepare("UPDATE records SET modseq = (SELECT modseq FROM modseq WHERE userid = ? AND type = ?), json = ? WHERE userid = ? AND type = ? AND id = ?"));
-
             // iterative style so we can use try!
             let mut updated = Vec::new();
+            let mut not_updated: BTreeMap<String,SetError> = BTreeMap::new();
+            let mut changes: Vec<(Json,Json)> = Vec::new();
             for (id, pr) in update.iter() {
-                let mut get_p = params.clone();
-                get_p.push(id);
-                let json = try!(self.exec_value::<String>("SELECT json FROM records WHERE userid = ? AND type = ? AND deleted = 0 AND id = ?", &get_p)); // XXX stmt version of exec_value?
+                let json = try!(self.store.get_records(userid, rectype, Some(&vec!(id.clone())), None)).pop();
 
                 // XXX None means not found, must return some sane error
 
-                if let Some(j) = json {
+                if let Some((_, j)) = json {
                     // XXX assuming parse success
-                    let r = R::from_json(&Json::from_str(j.as_ref()).unwrap()).unwrap().updated_with(&pr);
+                    let old = R::from_json(&Json::from_str(j.as_ref()).unwrap()).unwrap();
+                    // compare against the record as we'd serialise it, not as
+                    // stored, so numbers parsed back as U64 match their I64 originals
+                    let oj = old.to_json();
+                    let r = old.updated_with(&pr);
                     // XXX invalidArguments if trying to change id (or other immutable params?)
-                    let new_json = r.to_json().to_string();
-                    let id = r.id().clone();
-                    let mut p = params.clone();
-                    p.push(&new_json);
-                    p.push(&userid); // XXX merp, I need a better way to build these
-                    p.push(&rectype);
-                    p.push(&id);
-                    try!(update_stmt.execute(&p));
+                    let rj = r.to_json();
+                    if let Some(e) = try!(R::check_record(self, userid, Some(&oj), &rj)) {
+                        not_updated.insert(id.clone(), e);
+                        continue;
+                    }
+                    try!(self.store.update_record(userid, rectype, &r.id(), &rj.to_string()));
                     updated.push(r.id());
+                    changes.push((oj, rj));
                 }
             }
-            let not_updated: BTreeMap<String,SetError> = BTreeMap::new();
+            try!(R::records_updated(self, userid, &changes));
             Ok((updated, not_updated))
         })
     }
@@ -509,19 +434,141 @@ impl Db {
         // XXX spec doesn't list any reasons why a destroy could fail (SetError)
         // so for now we'll always return an empty notDestroyed list
         self.transaction(|| {
-            let mut stmt = try!(self.conn.prepare("UPDATE records SET deleted = 1, modseq = (SELECT modseq FROM modseq WHERE userid = ? AND type = ?) WHERE userid = ? AND type = ? AND id = ? AND deleted = 0"));
-            let params: Vec<&ToSql> = vec!(&userid, &rectype, &userid, &rectype);
-
             // iterative style so we can use try!
             let mut destroyed = Vec::new();
             for id in destroy.iter() {
-                let mut p = params.clone();
-                p.push(id);
-                try!(stmt.execute(&p));
+                try!(self.store.destroy_record(userid, rectype, id));
                 destroyed.push(id.clone());
             }
+            try!(R::records_destroyed(self, userid, &destroyed));
             let not_destroyed: BTreeMap<String,SetError> = BTreeMap::new();
             Ok((destroyed, not_destroyed))
         })
     }
+
+    // check that contacts being added to a group exist. ids already in the
+    // group aren't checked again, so a group holding a stale id can still be
+    // updated
+    fn check_group_contacts(&self, userid: i64, old: Option<&Json>, new: &Json) -> Result<Option<SetError>,DbError> {
+        let contact_ids = match new.find("contactIds").and_then(|j| j.as_array()) {
+            Some(a) => a,
+            None    => return Ok(None),
+        };
+
+        let old_ids = old.and_then(|o| o.find("contactIds")).and_then(|j| j.as_array());
+
+        let rectype = Contact::record_type();
+
+        for cid in contact_ids.iter() {
+            if let Some(o) = old_ids {
+                if o.contains(cid) { continue }
+            }
+            let id = match cid.as_string() {
+                Some(id) => id.to_string(),
+                None     => return Ok(Some(try!(set_error("invalidProperties", format!("contactIds: invalid contact id {}", cid))))),
+            };
+            if try!(self.store.get_records(userid, rectype, Some(&vec!(id.clone())), None)).len() == 0 {
+                return Ok(Some(try!(set_error("invalidProperties", format!("contactIds: contact {} not found", id)))));
+            }
+        }
+
+        Ok(None)
+    }
+
+    // remove destroyed contacts from any groups that reference them. the
+    // groups are rewritten directly in storage, so the ContactGroup state is
+    // bumped here if anything changed
+    fn remove_group_contacts(&self, userid: i64, contact_ids: &Vec<String>) -> Result<(),DbError> {
+        if contact_ids.len() == 0 { return Ok(()) }
+
+        let rectype = ContactGroup::record_type();
+
+        self.transaction(|| {
+            let mut changed: Vec<(String,String)> = Vec::new();
+            for (id, j) in try!(self.store.get_records(userid, rectype, None, None)).into_iter() {
+                let mut json = try!(Json::from_str(j.as_ref()));
+                let modified = match json {
+                    Json::Object(ref mut o) => match o.get_mut("contactIds") {
+                        Some(&mut Json::Array(ref mut a)) => {
+                            let before = a.len();
+                            a.retain(|j| match j.as_string() {
+                                Some(s) => !contact_ids.iter().any(|id| id == s),
+                                None    => true,
+                            });
+                            a.len() != before
+                        },
+                        _ => false,
+                    },
+                    _ => false,
+                };
+                if modified {
+                    changed.push((id, json.to_string()));
+                }
+            }
+
+            if changed.len() == 0 { return Ok(()) }
+
+            try!(self.next_state::<ContactGroup>(userid));
+
+            for &(ref id, ref json) in changed.iter() {
+                try!(self.store.update_record(userid, rectype, id, json));
+            }
+
+            Ok(())
+        })
+    }
+
+    // flag changes and moves alter the counts on the mailboxes holding the
+    // messages, before and after, so those mailboxes are marked changed under
+    // a new Mailbox state
+    fn touch_message_mailboxes(&self, userid: i64, changes: &Vec<(Json,Json)>) -> Result<(),DbError> {
+        let mut mailbox_ids: Vec<String> = Vec::new();
+        for &(ref old, ref new) in changes.iter() {
+            if changed_properties(old, new).len() == 0 { continue }
+            for j in [old, new].iter() {
+                if let Some(ids) = j.find("mailboxIds").and_then(|m| m.as_array()) {
+                    for id in ids.iter().filter_map(|id| id.as_string()) {
+                        if !mailbox_ids.iter().any(|m| m == id) {
+                            mailbox_ids.push(id.to_string());
+                        }
+                    }
+                }
+            }
+        }
+
+        if mailbox_ids.len() == 0 { return Ok(()) }
+
+        let rectype = Mailbox::record_type();
+
+        self.transaction(|| {
+            try!(self.next_state::<Mailbox>(userid));
+            for (id, json) in try!(self.store.get_records(userid, rectype, Some(&mailbox_ids), None)).into_iter() {
+                try!(self.store.update_record(userid, rectype, &id, &json));
+            }
+            Ok(())
+        })
+    }
+
+    pub fn set_push_callback(&self, userid: i64, url: &String, expires: i64) -> Result<i64,DbError> {
+        self.transaction(|| {
+            let states = try!(self.get_states(userid));
+            self.store.set_push_callback(userid, url, expires, &states)
+        })
+    }
+
+    pub fn get_push_callbacks(&self) -> Result<Vec<PushCallback>,DbError> {
+        self.store.get_push_callbacks()
+    }
+
+    pub fn set_push_callback_states(&self, id: i64, states: &BTreeMap<String,String>) -> Result<(),DbError> {
+        self.store.set_push_callback_states(id, states)
+    }
+
+    pub fn remove_push_callback(&self, id: i64) -> Result<(),DbError> {
+        self.store.remove_push_callback(id)
+    }
+
+    pub fn expire_push_callbacks(&self, now: i64) -> Result<usize,DbError> {
+        self.store.expire_push_callbacks(now)
+    }
 }
@@ -0,0 +1,156 @@
+use std::io::Write;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use hyper::server::Response;
+use hyper::method::Method::Get;
+use hyper::status::StatusCode;
+use hyper::header;
+
+use rustc_serialize::json::ToJson;
+
+use http_handler::{StatusBody, finish_response};
+
+use pool::DbPool;
+use notify;
+use util::query_param;
+
+// send a ping if nothing else has been sent for this long
+const PING_INTERVAL: u64 = 30;
+
+// each open stream holds one of hyper's worker threads for as long as the
+// client stays connected. past this many, new streams are refused. the server
+// is given this many threads on top of those for ordinary requests
+pub const MAX_STREAMS: usize = 16;
+
+static STREAMS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+// a slot in the open stream count, released when dropped
+struct StreamSlot;
+
+impl StreamSlot {
+    fn take() -> Option<StreamSlot> {
+        if STREAMS.fetch_add(1, Ordering::SeqCst) >= MAX_STREAMS {
+            STREAMS.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(StreamSlot)
+    }
+}
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        STREAMS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// states are modseqs. changes are published after their transaction commits,
+// so they can arrive out of order; only move a state forward
+fn is_newer(state: &String, current: Option<&String>) -> bool {
+    match (state.parse::<i64>(), current.and_then(|c| c.parse::<i64>().ok())) {
+        (Ok(s), Some(c)) => s > c,
+        (Ok(_), None)    => true,
+        (Err(_), _)      => false,
+    }
+}
+
+fn requested_types(path: &String) -> Option<Vec<String>> {
+    match query_param(path, "types") {
+        None                    => None,
+        Some(ref t) if t == "*" => None,
+        Some(t)                 => Some(t.split(',').filter(|t| t.len() > 0).map(|t| t.to_string()).collect()),
+    }
+}
+
+pub fn handler(path: &String, mut res: Response, pool: &DbPool) {
+    let _slot = match StreamSlot::take() {
+        Some(s) => s,
+        None    => {
+            finish_response(Get, path, res, StatusBody::new(StatusCode::ServiceUnavailable, Some("too many event streams".to_string().into_bytes())));
+            return;
+        },
+    };
+
+    let types = requested_types(path);
+
+    let userid = 1; // XXX get userid from auth
+
+    // subscribe before taking the initial states so nothing is missed in between
+    let changes = notify::subscribe();
+
+    let initial = match pool.get() {
+        Ok(db) => match db.get_states(userid) {
+            Ok(s)  => s,
+            Err(e) => {
+                finish_response(Get, path, res, StatusBody::new(StatusCode::InternalServerError, Some(format!("{}", e).into_bytes())));
+                return;
+            },
+        },
+        Err(e) => {
+            finish_response(Get, path, res, StatusBody::new(StatusCode::ServiceUnavailable, Some(format!("{}", e).into_bytes())));
+            return;
+        },
+    };
+
+    let wanted = |t: &str| match types {
+        None         => true,
+        Some(ref ts) => ts.iter().any(|w| w == t),
+    };
+
+    let mut states = initial.into_iter().filter(|&(ref k,_)| wanted(&k[..])).collect::<BTreeMap<_,_>>();
+
+    {
+        let headers = res.headers_mut();
+        headers.set(header::ContentType("text/event-stream".parse().unwrap()));
+        headers.set(header::CacheControl(vec!(header::CacheDirective::NoCache)));
+    }
+
+    *res.status_mut() = StatusCode::Ok;
+
+    info!("{} {} => {} (event stream)", Get, path, StatusCode::Ok);
+
+    let mut res = match res.start() {
+        Ok(res) => res,
+        Err(e)  => {
+            error!("response error: {}", e);
+            return;
+        },
+    };
+
+    let mut event = Some(format!("event: state\ndata: {}\n\n", states.to_json()));
+    let mut last_sent = Instant::now();
+
+    loop {
+        if let Some(ref e) = event {
+            if let Err(e) = res.write_all(e.as_bytes()).and_then(|_| res.flush()) {
+                info!("eventsource: client went away: {}", e);
+                break;
+            }
+            last_sent = Instant::now();
+        }
+
+        let ping_in = Duration::from_secs(PING_INTERVAL).checked_sub(last_sent.elapsed()).unwrap_or(Duration::from_secs(0));
+
+        event = match changes.recv_timeout(ping_in) {
+            Ok(c) => {
+                let mut changed = false;
+                for c in Some(c).into_iter().chain(changes.try_iter()) {
+                    if c.userid == userid && wanted(c.type_name) && is_newer(&c.state, states.get(c.type_name)) {
+                        states.insert(c.type_name.to_string(), c.state);
+                        changed = true;
+                    }
+                }
+                match changed {
+                    true  => Some(format!("event: state\ndata: {}\n\n", states.to_json())),
+                    false => None,
+                }
+            },
+            Err(RecvTimeoutError::Timeout)      => Some("event: ping\ndata: {}\n\n".to_string()),
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+    }
+
+    res.end().ok();
+}
@@ -1,6 +1,6 @@
 use std::io::{Read, Write};
 
-use hyper::server::{Request, Response};
+use hyper::server::{Handler, Request, Response};
 use hyper::method::Method;
 use hyper::method::Method::{Post, Get, Head};
 use hyper::status::StatusCode;
@@ -10,6 +10,13 @@ use hyper::header;
 use jmap_handler::handler as jmap_handler;
 use upload_handler::handler as upload_handler;
 use static_handler::handler as static_handler;
+use eventsource_handler::handler as eventsource_handler;
+use push_handler::handler as push_handler;
+use ical_handler::{export_handler as ical_export_handler, import_handler as ical_import_handler};
+use vcard_handler::{export_handler as vcard_export_handler, import_handler as vcard_import_handler};
+
+use pool::DbPool;
+use util::path_only;
 
 pub struct StatusBody {
     pub code: StatusCode,
@@ -21,7 +28,7 @@ impl StatusBody {
     }
 }
 
-fn finish_response(method: Method, path: &String, mut res: Response, out: StatusBody) {
+pub fn finish_response(method: Method, path: &String, mut res: Response, out: StatusBody) {
     *res.status_mut() = out.code;
 
     info!("{} {} => {}", method, path, out.code);
@@ -43,7 +50,23 @@ fn finish_response(method: Method, path: &String, mut res: Response, out: Status
         };
 }
 
-pub fn handler(mut req: Request, mut res: Response) {
+pub struct HttpHandler {
+    pool: DbPool,
+}
+
+impl HttpHandler {
+    pub fn new(pool: DbPool) -> HttpHandler {
+        HttpHandler { pool: pool }
+    }
+}
+
+impl Handler for HttpHandler {
+    fn handle<'a, 'k>(&'a self, req: Request<'a, 'k>, res: Response<'a>) {
+        handler(&self.pool, req, res)
+    }
+}
+
+fn handler(pool: &DbPool, mut req: Request, mut res: Response) {
     res.headers_mut().set(header::Server("salada/0.0.5".to_string()));
 
     let method = req.method.clone();
@@ -51,7 +74,7 @@ pub fn handler(mut req: Request, mut res: Response) {
 
     match (method, uri) {
         (Post, AbsolutePath(ref path)) if path == "/jmap/" => {
-            let sb = jmap_handler(req);
+            let sb = jmap_handler(req, pool);
             finish_response(Post, path, res, sb)
         },
 
@@ -60,6 +83,35 @@ pub fn handler(mut req: Request, mut res: Response) {
             finish_response(Post, path, res, sb)
         },
 
+        (Post, AbsolutePath(ref path)) if path == "/push/" => {
+            let sb = push_handler(req, pool);
+            finish_response(Post, path, res, sb)
+        },
+
+        (Post, AbsolutePath(ref path)) if path_only(path) == "/ical/" => {
+            let sb = ical_import_handler(path, req, pool);
+            finish_response(Post, path, res, sb)
+        },
+
+        (Post, AbsolutePath(ref path)) if path_only(path) == "/vcard/" => {
+            let sb = vcard_import_handler(path, req, pool);
+            finish_response(Post, path, res, sb)
+        },
+
+        (Get, AbsolutePath(ref path)) if path_only(path) == "/eventsource/" => {
+            eventsource_handler(path, res, pool)
+        },
+
+        (Get, AbsolutePath(ref path)) if path_only(path) == "/ical/" => {
+            let sb = ical_export_handler(path, &mut res, pool);
+            finish_response(Get, path, res, sb)
+        },
+
+        (Get, AbsolutePath(ref path)) if path_only(path) == "/vcard/" => {
+            let sb = vcard_export_handler(path, &mut res, pool);
+            finish_response(Get, path, res, sb)
+        },
+
         (Get, AbsolutePath(ref path)) => {
             let sb = static_handler(path, &mut res, true);
             finish_response(Get, path, res, sb)
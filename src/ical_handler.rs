@@ -0,0 +1,645 @@
+use std::io::Read;
+use std::collections::BTreeMap;
+
+use hyper::server::{Request, Response};
+use hyper::status::StatusCode;
+use hyper::header;
+
+use rustc_serialize::json::{Json, ToJson};
+
+use jmap::{Calendar, CalendarEvent};
+use jmap::record::Record;
+use jmap::parse::FromJson;
+
+use time;
+
+use http_handler::StatusBody;
+
+use pool::DbPool;
+use db::{Db, DbError};
+use util::{query_param, escape_text, unescape_text, fold_line, parse_components, Component, ContentLine};
+use tz::TimeZone;
+
+// recurrence properties and the RRULE parts they become. values are written
+// out as stored
+const RRULE_PARTS: [(&'static str, &'static str); 14] = [
+    ("frequency",      "FREQ"),
+    ("interval",       "INTERVAL"),
+    ("count",          "COUNT"),
+    ("until",          "UNTIL"),
+    ("firstDayOfWeek", "WKST"),
+    ("byMonth",        "BYMONTH"),
+    ("byWeekNo",       "BYWEEKNO"),
+    ("byYearDay",      "BYYEARDAY"),
+    ("byDate",         "BYMONTHDAY"),
+    ("byDay",          "BYDAY"),
+    ("byHour",         "BYHOUR"),
+    ("byMinute",       "BYMINUTE"),
+    ("bySecond",       "BYSECOND"),
+    ("bySetPosition",  "BYSETPOS"),
+];
+
+const WEEKDAYS: [&'static str; 7] = ["SU", "MO", "TU", "WE", "TH", "FR", "SA"];
+
+// text properties and the lines they're written as
+const TEXT_PROPERTIES: [(&'static str, &'static str); 3] = [
+    ("summary",     "SUMMARY"),
+    ("description", "DESCRIPTION"),
+    ("location",    "LOCATION"),
+];
+
+// properties an override can't change, so never part of an exception's patch
+const NOT_OVERRIDDEN: [&'static str; 4] = ["calendarId", "recurrence", "inclusions", "exceptions"];
+
+fn str_prop<'a>(j: &'a Json, name: &str) -> Option<&'a str> {
+    j.find(name).and_then(|v| v.as_string())
+}
+
+// "2015-06-12T10:00:00" => "20150612T100000"
+fn ical_datetime(s: &str) -> String {
+    s.chars().filter(|c| *c != '-' && *c != ':').collect()
+}
+
+// zones we can describe with a VTIMEZONE, by name
+type Zones = BTreeMap<String,TimeZone>;
+
+// times in a zone we have no VTIMEZONE for are written as floating local
+// times, since a TZID without one isn't valid
+fn date_line(name: &str, value: &str, tz: Option<&str>, all_day: bool, zones: &Zones) -> String {
+    let tz = tz.and_then(|z| zones.get(z)).map(|z| &z.id[..]);
+    match (all_day, tz) {
+        (true, _)        => format!("{};VALUE=DATE:{}", name, ical_datetime(&value[..value.find('T').unwrap_or(value.len())])),
+        (false, Some(z)) => format!("{};TZID={}:{}", name, z, ical_datetime(value)),
+        (false, None)    => format!("{}:{}", name, ical_datetime(value)),
+    }
+}
+
+// numeric weekdays are 0 = Sunday, with the occurrence in the month or year
+// as a multiple of 7 (so 8 is the first Monday, -6 the last Monday)
+fn weekday(d: i64) -> String {
+    let (n, wd) = (d / 7, ((d % 7) + 7) % 7);
+    let n = if d < 0 && wd != 0 { n - 1 } else { n };
+    match n {
+        0 => WEEKDAYS[wd as usize].to_string(),
+        _ => format!("{}{}", n, WEEKDAYS[wd as usize]),
+    }
+}
+
+fn rrule_number(part: &str, n: i64) -> String {
+    match part {
+        "WKST" | "BYDAY" => weekday(n),
+        _                => n.to_string(),
+    }
+}
+
+fn rrule_value(part: &str, v: &Json) -> Option<String> {
+    match *v {
+        Json::String(ref s) => Some(match part {
+            "UNTIL" => ical_datetime(s),
+            _       => s.to_uppercase(),
+        }),
+        Json::I64(n) => Some(rrule_number(part, n)),
+        Json::U64(n) => Some(rrule_number(part, n as i64)),
+        Json::Array(ref a) => {
+            let vals: Vec<String> = a.iter().filter_map(|v| rrule_value(part, v)).collect();
+            match vals.len() {
+                0 => None,
+                _ => Some(vals.join(",")),
+            }
+        },
+        _ => None,
+    }
+}
+
+fn rrule(recurrence: &Json) -> Option<String> {
+    let parts: Vec<String> = RRULE_PARTS.iter()
+        .filter_map(|&(prop, part)| recurrence.find(prop).and_then(|v| rrule_value(part, v)).map(|v| format!("{}={}", part, v)))
+        .collect();
+    match parts.len() {
+        0 => None,
+        _ => Some(format!("RRULE:{}", parts.join(";"))),
+    }
+}
+
+fn partstat(rsvp: Option<&str>) -> &'static str {
+    match rsvp {
+        Some("yes")   => "ACCEPTED",
+        Some("maybe") => "TENTATIVE",
+        Some("no")    => "DECLINED",
+        _             => "NEEDS-ACTION",
+    }
+}
+
+fn cal_address(name: &str, p: &Json) -> Option<String> {
+    let email = match str_prop(p, "email") {
+        Some(e) => e,
+        None    => return None,
+    };
+
+    let mut line = name.to_string();
+    if let Some(cn) = str_prop(p, "name") {
+        line.push_str(&format!(";CN=\"{}\"", cn.replace("\"", "")));
+    }
+    if name == "ATTENDEE" {
+        line.push_str(&format!(";PARTSTAT={}", partstat(str_prop(p, "rsvp"))));
+    }
+    line.push_str(":mailto:");
+    line.push_str(email);
+
+    Some(line)
+}
+
+// write one VEVENT. overrides of a recurring event get a RECURRENCE-ID and
+// no recurrence of their own
+fn write_event(out: &mut String, e: &Json, id: &str, recurrence_id: Option<&str>, dtstamp: &str, zones: &Zones) {
+    let all_day = e.find("isAllDay").and_then(|v| v.as_boolean()).unwrap_or(false);
+    let start_tz = str_prop(e, "startTimeZone");
+    let end_tz = str_prop(e, "endTimeZone").or(start_tz);
+
+    let mut lines: Vec<String> = vec!(
+        "BEGIN:VEVENT".to_string(),
+        format!("UID:{}", escape_text(id)),
+        format!("DTSTAMP:{}", dtstamp),
+    );
+
+    if let Some(r) = recurrence_id {
+        lines.push(date_line("RECURRENCE-ID", r, start_tz, all_day, zones));
+    }
+    if let Some(start) = str_prop(e, "start") {
+        lines.push(date_line("DTSTART", start, start_tz, all_day, zones));
+    }
+    if let Some(end) = str_prop(e, "end") {
+        lines.push(date_line("DTEND", end, end_tz, all_day, zones));
+    }
+
+    for &(prop, name) in TEXT_PROPERTIES.iter() {
+        if let Some(v) = str_prop(e, prop) {
+            lines.push(format!("{}:{}", name, escape_text(v)));
+        }
+    }
+
+    if let Some(free) = e.find("showAsFree").and_then(|v| v.as_boolean()) {
+        lines.push(format!("TRANSP:{}", if free { "TRANSPARENT" } else { "OPAQUE" }));
+    }
+
+    if recurrence_id.is_none() {
+        if let Some(r) = e.find("recurrence").and_then(|r| rrule(r)) {
+            lines.push(r);
+        }
+        if let Some(inclusions) = e.find("inclusions").and_then(|i| i.as_array()) {
+            for d in inclusions.iter().filter_map(|d| d.as_string()) {
+                lines.push(date_line("RDATE", d, start_tz, all_day, zones));
+            }
+        }
+        if let Some(exceptions) = e.find("exceptions").and_then(|x| x.as_object()) {
+            for (d, patch) in exceptions.iter() {
+                if patch.is_null() {
+                    lines.push(date_line("EXDATE", d, start_tz, all_day, zones));
+                }
+            }
+        }
+    }
+
+    if let Some(o) = e.find("organizer").and_then(|o| cal_address("ORGANIZER", o)) {
+        lines.push(o);
+    }
+    if let Some(attendees) = e.find("attendees").and_then(|a| a.as_array()) {
+        for a in attendees.iter().filter_map(|a| cal_address("ATTENDEE", a)) {
+            lines.push(a);
+        }
+    }
+
+    lines.push("END:VEVENT".to_string());
+
+    for l in lines.iter() {
+        out.push_str(&fold_line(l));
+    }
+
+    // modified occurrences are the base event with the exception's changes on top
+    if recurrence_id.is_none() {
+        if let (Some(base), Some(exceptions)) = (e.as_object(), e.find("exceptions").and_then(|x| x.as_object())) {
+            for (d, patch) in exceptions.iter() {
+                if let Some(p) = patch.as_object() {
+                    let mut o = base.clone();
+                    for (k, v) in p.iter() {
+                        o.insert(k.clone(), v.clone());
+                    }
+                    write_event(out, &Json::Object(o), id, Some(d), dtstamp, zones);
+                }
+            }
+        }
+    }
+}
+
+fn export_calendar(db: &Db, userid: i64, calendar_id: &String) -> Result<Option<(String,String)>,DbError> {
+    let (calendars, events) = try!(db.transaction(|| {
+        Ok((
+            try!(db.get_records::<Calendar>(userid, Some(&vec!(calendar_id.clone())), None)),
+            try!(db.get_records::<CalendarEvent>(userid, None, None)),
+        ))
+    }));
+
+    let calendar = match calendars.into_iter().next() {
+        Some(c) => c.to_json(),
+        None    => return Ok(None),
+    };
+    let name = str_prop(&calendar, "name").unwrap_or("calendar").to_string();
+
+    let dtstamp = time::strftime("%Y%m%dT%H%M%SZ", &time::now_utc()).unwrap();
+
+    let mut out = String::new();
+    for l in ["BEGIN:VCALENDAR", "VERSION:2.0", "PRODID:-//salada//salada//EN"].iter() {
+        out.push_str(&fold_line(l));
+    }
+    out.push_str(&fold_line(&format!("X-WR-CALNAME:{}", escape_text(&name))));
+
+    let events: Vec<Json> = events.iter().map(|e| e.to_json()).filter(|e| str_prop(e, "calendarId") == Some(&calendar_id[..])).collect();
+
+    // every zone named by an event or one of its overrides needs a VTIMEZONE
+    let mut zones: Zones = BTreeMap::new();
+    for e in events.iter() {
+        let patches: Vec<&Json> = e.find("exceptions").and_then(|x| x.as_object()).map(|x| x.values().collect()).unwrap_or(vec!());
+        for j in Some(e).into_iter().chain(patches.into_iter()) {
+            for z in ["startTimeZone", "endTimeZone"].iter().filter_map(|p| str_prop(j, p)) {
+                if zones.contains_key(z) { continue }
+                match TimeZone::load(z) {
+                    Some(tz) => { zones.insert(z.to_string(), tz); },
+                    None     => info!("ical: no usable definition for time zone {}, writing floating times", z),
+                }
+            }
+        }
+    }
+    for tz in zones.values() {
+        for l in tz.vtimezone().iter() {
+            out.push_str(&fold_line(l));
+        }
+    }
+
+    for e in events.iter() {
+        let id = str_prop(e, "id").unwrap_or("").to_string();
+        write_event(&mut out, e, &id, None, &dtstamp, &zones);
+    }
+
+    out.push_str(&fold_line("END:VCALENDAR"));
+
+    Ok(Some((name, out)))
+}
+
+// the calendar name cut down to something safe to put in a header: printable
+// ascii only, so no line breaks, and no quotes to break out of the filename
+fn download_filename(name: &str) -> String {
+    let safe: String = name.chars().filter(|c| *c >= ' ' && *c <= '~' && *c != '"' && *c != '\\').collect();
+    match safe.trim().len() {
+        0 => "calendar.ics".to_string(),
+        _ => format!("{}.ics", safe.trim()),
+    }
+}
+
+// GET /ical/?calendar=<id> downloads a whole calendar as an iCalendar file
+pub fn export_handler(path: &String, res: &mut Response, pool: &DbPool) -> StatusBody {
+    let calendar_id = match query_param(path, "calendar") {
+        Some(id) => id,
+        None     => return StatusBody::new(StatusCode::BadRequest, Some("calendar: required".to_string().into_bytes())),
+    };
+
+    let userid = 1; // XXX get userid from auth
+
+    let db = match pool.get() {
+        Ok(db) => db,
+        Err(e) => return StatusBody::new(StatusCode::ServiceUnavailable, Some(format!("{}", e).into_bytes())),
+    };
+
+    match export_calendar(&db, userid, &calendar_id) {
+        Ok(Some((name, ics))) => {
+            let headers = res.headers_mut();
+            headers.set(header::ContentType("text/calendar; charset=utf-8".parse().unwrap()));
+            headers.set(header::ContentLength(ics.len() as u64));
+            headers.set_raw("Content-Disposition", vec!(format!("attachment; filename=\"{}\"", download_filename(&name)).into_bytes()));
+            StatusBody::new(StatusCode::Ok, Some(ics.into_bytes()))
+        },
+        Ok(None) => StatusBody::new(StatusCode::NotFound, None),
+        Err(e)   => StatusBody::new(StatusCode::InternalServerError, Some(format!("{}", e).into_bytes())),
+    }
+}
+
+// "20150612T100000Z" => ("2015-06-12T10:00:00", date, utc). dates become
+// midnight
+fn jmap_datetime(s: &str) -> Option<(String,bool,bool)> {
+    let utc = s.ends_with('Z');
+    let s = s.trim_right_matches('Z');
+    let valid = s.chars().enumerate().all(|(i, c)| if i == 8 { c == 'T' } else { c.is_digit(10) });
+    match (s.len(), valid) {
+        (8, true)  => Some((format!("{}-{}-{}T00:00:00", &s[0..4], &s[4..6], &s[6..8]), true, utc)),
+        (15, true) => Some((format!("{}-{}-{}T{}:{}:{}", &s[0..4], &s[4..6], &s[6..8], &s[9..11], &s[11..13], &s[13..15]), false, utc)),
+        _          => None,
+    }
+}
+
+// a date or date-time line as (value, time zone, all day). UTC times are
+// stored in Etc/UTC
+// XXX VTIMEZONE components aren't read, so a TZID that isn't an Olson name
+//     (as some clients write) is kept as is
+fn event_time(l: &ContentLine) -> Option<(String,Option<String>,bool)> {
+    jmap_datetime(l.value.trim()).map(|(v, date, utc)| {
+        let tz = match (date, utc, l.param("TZID")) {
+            (true, _, _)         => None,
+            (false, true, _)     => Some("Etc/UTC".to_string()),
+            (false, false, tzid) => tzid.map(|z| z.trim_left_matches('/').to_string()),
+        };
+        (v, tz, date)
+    })
+}
+
+// "P1W", "-PT15M", "P1DT2H" as seconds
+fn duration(s: &str) -> Option<i64> {
+    let (sign, s) = match s.starts_with('-') {
+        true  => (-1, &s[1..]),
+        false => (1, s.trim_left_matches('+')),
+    };
+    if !s.starts_with('P') { return None }
+
+    let mut secs = 0;
+    let mut n = String::new();
+    for c in s[1..].chars() {
+        let unit = match c {
+            'W' => 7*24*3600,
+            'D' => 24*3600,
+            'H' => 3600,
+            'M' => 60,
+            'S' => 1,
+            'T' => continue,
+            c if c.is_digit(10) => {
+                n.push(c);
+                continue;
+            },
+            _ => return None,
+        };
+        secs += match n.parse::<i64>() {
+            Ok(v)  => v * unit,
+            Err(_) => return None,
+        };
+        n.clear();
+    }
+    Some(sign * secs)
+}
+
+fn add_seconds(datetime: &str, secs: i64) -> Option<String> {
+    time::strptime(datetime, "%Y-%m-%dT%H:%M:%S").ok().map(|tm| {
+        let t = time::at_utc(tm.to_timespec() + time::Duration::seconds(secs));
+        time::strftime("%Y-%m-%dT%H:%M:%S", &t).unwrap()
+    })
+}
+
+// "MO" => 1, "2TU" => 16, "-1SU" => -7. the inverse of weekday()
+fn parse_weekday(s: &str) -> Option<i64> {
+    let s = s.trim().to_uppercase();
+    let n = s.len();
+    if n < 2 || !s.is_char_boundary(n-2) { return None }
+    let wd = match WEEKDAYS.iter().position(|d| *d == &s[n-2..]) {
+        Some(wd) => wd as i64,
+        None     => return None,
+    };
+    let ord = match &s[..n-2] {
+        "" => 0,
+        o  => match o.trim_left_matches('+').parse::<i64>() {
+            Ok(o)  => o,
+            Err(_) => return None,
+        },
+    };
+    Some(ord * 7 + wd)
+}
+
+// RRULE value to a recurrence object, the inverse of rrule()
+fn parse_rrule(value: &str) -> Option<Json> {
+    let mut r = BTreeMap::new();
+    for part in value.trim().split(';') {
+        let mut kv = part.splitn(2, '=');
+        let (k, v) = match (kv.next(), kv.next()) {
+            (Some(k), Some(v)) => (k.to_uppercase(), v),
+            _                  => continue,
+        };
+        let prop = match RRULE_PARTS.iter().find(|&&(_, p)| p == k) {
+            Some(&(prop, _)) => prop,
+            None             => continue,
+        };
+        let j = match &k[..] {
+            "FREQ"               => v.to_lowercase().to_json(),
+            "UNTIL"              => match jmap_datetime(v) {
+                Some((d, _, _)) => d.to_json(),
+                None            => continue,
+            },
+            "INTERVAL" | "COUNT" => match v.parse::<i64>() {
+                Ok(n)  => n.to_json(),
+                Err(_) => continue,
+            },
+            "WKST"               => match parse_weekday(v) {
+                Some(n) => n.to_json(),
+                None    => continue,
+            },
+            "BYDAY"              => Json::Array(v.split(',').filter_map(parse_weekday).map(|n| n.to_json()).collect()),
+            _                    => Json::Array(v.split(',').filter_map(|n| n.trim().parse::<i64>().ok()).map(|n| n.to_json()).collect()),
+        };
+        r.insert(prop.to_string(), j);
+    }
+    match r.contains_key("frequency") {
+        true  => Some(Json::Object(r)),
+        false => None,
+    }
+}
+
+// ORGANIZER or ATTENDEE line to a participant, the inverse of cal_address()
+fn parse_address(l: &ContentLine) -> Json {
+    let v = l.value.trim();
+    let email = match v.to_lowercase().starts_with("mailto:") {
+        true  => &v[7..],
+        false => v,
+    };
+
+    let mut o = BTreeMap::new();
+    o.insert("name".to_string(), l.param("CN").unwrap_or("").to_json());
+    o.insert("email".to_string(), email.to_json());
+    o.insert("isYou".to_string(), false.to_json());
+    if l.name == "ATTENDEE" {
+        let rsvp = match l.param("PARTSTAT").map(|p| p.to_uppercase()) {
+            Some(ref p) if p == "ACCEPTED"  => "yes",
+            Some(ref p) if p == "TENTATIVE" => "maybe",
+            Some(ref p) if p == "DECLINED"  => "no",
+            _                               => "",
+        };
+        o.insert("rsvp".to_string(), rsvp.to_json());
+    }
+    Json::Object(o)
+}
+
+// dates from RDATE or EXDATE lines, which may hold several. periods
+// ("start/end") are reduced to their start
+fn date_list(e: &Component, name: &str) -> Vec<String> {
+    e.lines.iter()
+        .filter(|l| l.name == name)
+        .flat_map(|l| l.value.split(',').filter_map(|d| jmap_datetime(d.split('/').next().unwrap_or("").trim())).map(|(d, _, _)| d).collect::<Vec<_>>().into_iter())
+        .collect()
+}
+
+// one VEVENT as event json, the inverse of write_event()
+fn parse_event(e: &Component, calendar_id: &str) -> BTreeMap<String,Json> {
+    let mut o: BTreeMap<String,Json> = BTreeMap::new();
+    o.insert("calendarId".to_string(), calendar_id.to_json());
+
+    for &(prop, name) in TEXT_PROPERTIES.iter() {
+        if let Some(l) = e.line(name) {
+            o.insert(prop.to_string(), unescape_text(&l.value).to_json());
+        }
+    }
+
+    if let Some(l) = e.line("TRANSP") {
+        o.insert("showAsFree".to_string(), (l.value.trim().to_uppercase() == "TRANSPARENT").to_json());
+    }
+
+    if let Some((start, start_tz, all_day)) = e.line("DTSTART").and_then(event_time) {
+        // no end means a duration, or a day for dates and no time at all otherwise
+        let end = match e.line("DTEND").and_then(event_time) {
+            Some((end, end_tz, _)) => Some((end, end_tz)),
+            None                   => {
+                let d = e.line("DURATION").and_then(|l| duration(l.value.trim())).unwrap_or(if all_day { 24*3600 } else { 0 });
+                add_seconds(&start, d).map(|end| (end, start_tz.clone()))
+            },
+        };
+        if let Some((end, end_tz)) = end {
+            o.insert("end".to_string(), end.to_json());
+            o.insert("endTimeZone".to_string(), end_tz.to_json());
+        }
+        o.insert("start".to_string(), start.to_json());
+        o.insert("startTimeZone".to_string(), start_tz.to_json());
+        o.insert("isAllDay".to_string(), all_day.to_json());
+    }
+
+    if let Some(r) = e.line("RRULE").and_then(|l| parse_rrule(&l.value)) {
+        o.insert("recurrence".to_string(), r);
+    }
+    let inclusions = date_list(e, "RDATE");
+    if inclusions.len() > 0 {
+        o.insert("inclusions".to_string(), inclusions.to_json());
+    }
+    let exdates = date_list(e, "EXDATE");
+    if exdates.len() > 0 {
+        o.insert("exceptions".to_string(), Json::Object(exdates.into_iter().map(|d| (d, Json::Null)).collect()));
+    }
+
+    if let Some(l) = e.line("ORGANIZER") {
+        o.insert("organizer".to_string(), parse_address(l));
+    }
+    let attendees: Vec<Json> = e.lines.iter().filter(|l| l.name == "ATTENDEE").map(|l| parse_address(l)).collect();
+    if attendees.len() > 0 {
+        o.insert("attendees".to_string(), Json::Array(attendees));
+    }
+
+    o
+}
+
+// the events in a VCALENDAR, keyed by UID. overrides (VEVENTs with a
+// RECURRENCE-ID) become exceptions on the event they modify, holding only
+// what they change
+fn parse_events(cal: &Component, calendar_id: &str) -> BTreeMap<String,Json> {
+    let mut events: BTreeMap<String,BTreeMap<String,Json>> = BTreeMap::new();
+    let mut overrides = vec!();
+
+    for (i, e) in cal.components.iter().filter(|c| c.name == "VEVENT").enumerate() {
+        let uid = e.line("UID").map(|l| unescape_text(&l.value)).unwrap_or(format!("event {}", i));
+        let j = parse_event(e, calendar_id);
+        match e.line("RECURRENCE-ID").and_then(event_time) {
+            Some((rid, _, _)) => overrides.push((uid, rid, j)),
+            None              => { events.insert(uid, j); },
+        }
+    }
+
+    for (uid, rid, o) in overrides.into_iter() {
+        let base = match events.get_mut(&uid) {
+            Some(b) => b,
+            None    => {
+                // nothing to apply it to; keep it as an event of its own
+                events.insert(format!("{} {}", uid, rid), o);
+                continue;
+            },
+        };
+
+        let mut patch = BTreeMap::new();
+        for (k, v) in o.iter().filter(|&(k, _)| !NOT_OVERRIDDEN.contains(&&k[..])) {
+            if base.get(k) != Some(v) {
+                patch.insert(k.clone(), v.clone());
+            }
+        }
+        for k in base.keys().filter(|k| !NOT_OVERRIDDEN.contains(&&k[..]) && !o.contains_key(*k)) {
+            patch.insert(k.clone(), Json::Null);
+        }
+
+        let exceptions = base.entry("exceptions".to_string()).or_insert(Json::Object(BTreeMap::new()));
+        if let Json::Object(ref mut x) = *exceptions {
+            x.insert(rid, Json::Object(patch));
+        }
+    }
+
+    events.into_iter().map(|(uid, e)| (uid, Json::Object(e))).collect()
+}
+
+fn import_events(db: &Db, userid: i64, calendar_id: &String, create: &BTreeMap<String,<CalendarEvent as Record>::Partial>) -> Result<Option<(Vec<String>,BTreeMap<String,Json>)>,DbError> {
+    db.exclusive(|| {
+        if try!(db.get_records::<Calendar>(userid, Some(&vec!(calendar_id.clone())), None)).len() == 0 {
+            return Ok(None);
+        }
+
+        try!(db.next_state::<CalendarEvent>(userid));
+        let (created, not_created) = try!(db.create_records::<CalendarEvent>(userid, create));
+
+        let ids: Vec<String> = created.values().filter_map(|pr| pr.to_json().find("id").and_then(|id| id.as_string()).map(|id| id.to_string())).collect();
+        let errors: BTreeMap<String,Json> = not_created.iter().map(|(uid, e)| (uid.clone(), e.to_json())).collect();
+
+        Ok(Some((ids, errors)))
+    })
+}
+
+// POST /ical/?calendar=<id> creates an event in the calendar for each event
+// in the iCalendar file in the request body. the response is
+//   { "created": [ ids ], "notCreated": { "<uid>": SetError } }
+pub fn import_handler(path: &String, mut req: Request, pool: &DbPool) -> StatusBody {
+    let calendar_id = match query_param(path, "calendar") {
+        Some(id) => id,
+        None     => return StatusBody::new(StatusCode::BadRequest, Some("calendar: required".to_string().into_bytes())),
+    };
+
+    let mut body = String::new();
+    if let Err(e) = req.read_to_string(&mut body) {
+        return StatusBody::new(StatusCode::BadRequest, Some(format!("{}", e).into_bytes()));
+    }
+
+    let mut create = BTreeMap::new();
+    for cal in parse_components(&body).iter().filter(|c| c.name == "VCALENDAR") {
+        for (uid, e) in parse_events(cal, &calendar_id).into_iter() {
+            match <CalendarEvent as Record>::Partial::from_json(&e) {
+                Ok(pr) => { create.insert(uid, pr); },
+                Err(e) => return StatusBody::new(StatusCode::BadRequest, Some(format!("{}: {}", uid, DbError::from(e)).into_bytes())),
+            }
+        }
+    }
+    if create.len() == 0 {
+        return StatusBody::new(StatusCode::BadRequest, Some("no events found".to_string().into_bytes()));
+    }
+
+    let userid = 1; // XXX get userid from auth
+
+    let db = match pool.get() {
+        Ok(db) => db,
+        Err(e) => return StatusBody::new(StatusCode::ServiceUnavailable, Some(format!("{}", e).into_bytes())),
+    };
+
+    match import_events(&db, userid, &calendar_id, &create) {
+        Ok(Some((created, not_created))) => {
+            info!("imported {} events, {} failed", created.len(), not_created.len());
+            let mut out = BTreeMap::new();
+            out.insert("created".to_string(), created.to_json());
+            out.insert("notCreated".to_string(), not_created.to_json());
+            StatusBody::new(StatusCode::Ok, Some(out.to_json().to_string().into_bytes()))
+        },
+        Ok(None) => StatusBody::new(StatusCode::NotFound, None),
+        Err(e)   => StatusBody::new(StatusCode::InternalServerError, Some(format!("{}", e).into_bytes())),
+    }
+}
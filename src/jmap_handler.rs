@@ -13,7 +13,7 @@ use http_handler::StatusBody;
 use record::RecordHandler;
 
 use util::RequestContext;
-use db::Db;
+use pool::DbPool;
 
 macro_rules! make_crud_method_dispatcher {
     ($method: expr, $rmethods: expr, $r: expr,
@@ -37,16 +37,21 @@ macro_rules! make_crud_method_dispatcher {
 }
 
 
-pub fn handler(mut req: Request) -> StatusBody {
+pub fn handler(mut req: Request, pool: &DbPool) -> StatusBody {
 
     match Json::from_reader(&mut req) {
         Ok(j) => match RequestBatch::from_json(&j) {
             Ok(b) => {
                 let mut rbatch: ResponseBatch = ResponseBatch::default();
 
+                let db = match pool.get() {
+                    Ok(db) => db,
+                    Err(e) => return StatusBody::new(StatusCode::ServiceUnavailable, Some(format!("{}", e).into_bytes())),
+                };
+
                 let r = RequestContext {
                     userid: 1, // XXX get userid from auth
-                    db: Db::open().unwrap(),
+                    db: &db,
                 };
 
                 for method in b.0.into_iter() {
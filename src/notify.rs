@@ -0,0 +1,29 @@
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, Sender, Receiver};
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct StateChange {
+    pub userid:    i64,
+    pub type_name: &'static str,
+    pub state:     String,
+}
+
+lazy_static! {
+    static ref SUBSCRIBERS: Mutex<Vec<Sender<StateChange>>> = Mutex::new(Vec::new());
+}
+
+// receive every state change committed from now on. dropping the receiver
+// unsubscribes
+pub fn subscribe() -> Receiver<StateChange> {
+    let (tx, rx) = channel();
+    SUBSCRIBERS.lock().unwrap().push(tx);
+    rx
+}
+
+pub fn publish(changes: Vec<StateChange>) {
+    let mut subscribers = SUBSCRIBERS.lock().unwrap();
+    for change in changes.into_iter() {
+        debug!("state change: user {} {} => {}", change.userid, change.type_name, change.state);
+        subscribers.retain(|tx| tx.send(change.clone()).is_ok());
+    }
+}
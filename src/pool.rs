@@ -0,0 +1,118 @@
+use std::sync::{Mutex, Condvar};
+use std::ops::Deref;
+use std::time::{Duration, Instant};
+
+use db::{Db, DbError};
+use db::DbError::*;
+
+// how long to wait for a connection to be returned before giving up
+const WAIT_TIMEOUT: u64 = 5;
+
+struct Conns {
+    idle: Vec<Db>,
+    live: usize, // idle and borrowed
+}
+
+pub struct DbPool {
+    size:      usize,
+    conns:     Mutex<Conns>,
+    available: Condvar,
+
+    // opens connections to bring the pool back up to size
+    open:      Box<Fn() -> Result<Db,DbError> + Send + Sync>,
+}
+
+impl DbPool {
+    pub fn new<F>(size: usize, open: F) -> Result<DbPool,DbError> where F: Fn() -> Result<Db,DbError> + Send + Sync + 'static {
+        let mut dbs = Vec::with_capacity(size);
+        for _ in 0..size {
+            dbs.push(try!(open()));
+        }
+
+        info!("opened {} database connections", size);
+
+        Ok(DbPool {
+            size:      size,
+            conns:     Mutex::new(Conns { idle: dbs, live: size }),
+            available: Condvar::new(),
+            open:      Box::new(open),
+        })
+    }
+
+    pub fn get(&self) -> Result<PooledDb,DbError> {
+        let deadline = Instant::now() + Duration::from_secs(WAIT_TIMEOUT);
+
+        let mut conns = self.conns.lock().unwrap();
+        loop {
+            if let Some(db) = conns.idle.pop() {
+                return Ok(PooledDb {
+                    pool: self,
+                    db:   Some(db),
+                });
+            }
+
+            // a connection was thrown away; open another in its place. the
+            // slot is taken before unlocking so others don't open one too
+            if conns.live < self.size {
+                conns.live += 1;
+                drop(conns);
+                return match (self.open)() {
+                    Ok(db) => Ok(PooledDb {
+                        pool: self,
+                        db:   Some(db),
+                    }),
+                    Err(e) => {
+                        self.conns.lock().unwrap().live -= 1;
+                        Err(e)
+                    },
+                };
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(InternalError("no database connection available".to_string()));
+            }
+
+            conns = self.available.wait_timeout(conns, deadline - now).unwrap().0;
+        }
+    }
+
+    fn put(&self, db: Db) {
+        let mut conns = self.conns.lock().unwrap();
+
+        // a connection still in a transaction would hold its locks and hand
+        // the half-done transaction to the next user. throw it away, and
+        // let the next get() open a new one
+        match db.in_transaction() {
+            false => conns.idle.push(db),
+            true  => {
+                error!("database connection returned with an open transaction, discarding it");
+                drop(db);
+                conns.live -= 1;
+            },
+        }
+
+        self.available.notify_one();
+    }
+}
+
+// a connection borrowed from the pool, returned when dropped
+pub struct PooledDb<'a> {
+    pool: &'a DbPool,
+    db:   Option<Db>,
+}
+
+impl<'a> Deref for PooledDb<'a> {
+    type Target = Db;
+    fn deref(&self) -> &Db {
+        self.db.as_ref().unwrap()
+    }
+}
+
+impl<'a> Drop for PooledDb<'a> {
+    fn drop(&mut self) {
+        if let Some(db) = self.db.take() {
+            self.pool.put(db);
+        }
+    }
+}
@@ -0,0 +1,278 @@
+use std::io::Read;
+use std::collections::{HashMap, HashSet};
+use std::thread;
+use std::time::Duration;
+use std::sync::mpsc::RecvTimeoutError;
+use std::cmp;
+use std::env;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream, ToSocketAddrs};
+
+use hyper;
+use hyper::Client;
+use hyper::client::RedirectPolicy;
+use hyper::header::ContentType;
+use hyper::net::{NetworkConnector, HttpStream, HttpsStream, Openssl, Ssl};
+
+use rustc_serialize::json::ToJson;
+
+use time;
+
+use db::{Db, DbError};
+use notify;
+
+// how often to wake up to retry failed deliveries and expire callbacks
+const RETRY_INTERVAL: u64 = 1;
+
+// retry delays double after each failed delivery, up to this many seconds
+const MAX_BACKOFF: i64 = 300;
+
+// callbacks that fail this many deliveries in a row are removed
+const MAX_FAILURES: u32 = 10;
+
+// give up on a callback request after this many seconds without progress
+const POST_TIMEOUT: u64 = 10;
+
+// set this in the environment to allow callbacks to loopback, link-local and
+// private addresses, eg when the receiver runs on the same machine during
+// development
+const ALLOW_LOCAL_ENV: &'static str = "SALADA_PUSH_ALLOW_LOCAL";
+
+struct Retry {
+    failures:     u32,
+    next_attempt: i64,
+}
+
+// host and port from a http or https url
+fn host_port(url: &str) -> Option<(String,u16)> {
+    let (rest, default_port) = if url.starts_with("http://") {
+        (&url[7..], 80)
+    } else if url.starts_with("https://") {
+        (&url[8..], 443)
+    } else {
+        return None;
+    };
+
+    let authority = &rest[..rest.find(|c| c == '/' || c == '?' || c == '#').unwrap_or(rest.len())];
+    let hostport = match authority.rfind('@') {
+        Some(n) => &authority[n+1..],
+        None    => authority,
+    };
+
+    // "[::1]:8080", "example.com:8080", "example.com"
+    let (host, port) = if hostport.starts_with('[') {
+        match hostport.find(']') {
+            Some(n) => (&hostport[1..n], &hostport[n+1..]),
+            None    => return None,
+        }
+    } else {
+        match hostport.rfind(':') {
+            Some(n) => (&hostport[..n], &hostport[n..]),
+            None    => (hostport, ""),
+        }
+    };
+
+    let port = match port {
+        ""                      => default_port,
+        p if p.starts_with(':') => match p[1..].parse::<u16>() {
+            Ok(p)  => p,
+            Err(_) => return None,
+        },
+        _ => return None,
+    };
+
+    match host.len() {
+        0 => None,
+        _ => Some((host.to_string(), port)),
+    }
+}
+
+fn is_internal_v4(a: &Ipv4Addr) -> bool {
+    let o = a.octets();
+    a.is_loopback() || a.is_link_local() || a.is_private() || a.is_unspecified() || a.is_broadcast() ||
+        (o[0] == 100 && (o[1] & 0xc0) == 64) // shared address space, 100.64/10
+}
+
+// addresses only reachable from this machine or its own networks
+fn is_internal(addr: &IpAddr) -> bool {
+    match *addr {
+        IpAddr::V4(ref a) => is_internal_v4(a),
+        IpAddr::V6(ref a) => {
+            let s = a.segments();
+            a.is_loopback() || a.is_unspecified() ||
+                (s[0] & 0xffc0) == 0xfe80 || // link-local, fe80::/10
+                (s[0] & 0xfe00) == 0xfc00 || // unique local, fc00::/7
+                // ipv4-mapped, ::ffff:a.b.c.d
+                (s[0..5].iter().all(|s| *s == 0) && s[5] == 0xffff &&
+                    is_internal_v4(&Ipv4Addr::new((s[6] >> 8) as u8, s[6] as u8, (s[7] >> 8) as u8, s[7] as u8)))
+        },
+    }
+}
+
+// resolve a callback url to the address to connect to. callbacks to this
+// machine or its networks are refused, so the endpoint can't be used to
+// make requests to services that aren't otherwise reachable. done on every
+// post, and the post goes to the address checked here, so a name that later
+// resolves somewhere else can't get past it
+fn resolve(url: &String) -> Result<SocketAddr,String> {
+    let (host, port) = match host_port(url) {
+        Some(hp) => hp,
+        None     => return Err("invalid url".to_string()),
+    };
+
+    let addrs: Vec<SocketAddr> = match (&host[..], port).to_socket_addrs() {
+        Ok(a)  => a.collect(),
+        Err(e) => return Err(format!("{}: {}", host, e)),
+    };
+
+    if env::var(ALLOW_LOCAL_ENV).is_err() {
+        if let Some(addr) = addrs.iter().find(|a| is_internal(&a.ip())) {
+            return Err(format!("{}: internal address {} not allowed", host, addr.ip()));
+        }
+    }
+
+    match addrs.into_iter().next() {
+        Some(addr) => Ok(addr),
+        None       => Err(format!("{}: no addresses", host)),
+    }
+}
+
+// connects to a fixed address, whatever host hyper asks for. tls still
+// checks the certificate against the url's host
+struct PinnedConnector {
+    addr: SocketAddr,
+    ssl:  Openssl,
+}
+
+impl NetworkConnector for PinnedConnector {
+    type Stream = HttpsStream<<Openssl as Ssl>::Stream>;
+
+    fn connect(&self, host: &str, _port: u16, scheme: &str) -> hyper::Result<Self::Stream> {
+        let stream = HttpStream(try!(TcpStream::connect(self.addr)));
+        match scheme {
+            "https" => Ok(HttpsStream::Https(try!(self.ssl.wrap_client(stream, host)))),
+            _       => Ok(HttpsStream::Http(stream)),
+        }
+    }
+}
+
+// POST a json body to a callback url, returning the response body if the
+// receiver accepted it. redirects aren't followed, since they could lead
+// anywhere
+pub fn post(url: &String, body: &String) -> Result<String,String> {
+    let addr = try!(resolve(url));
+
+    let mut client = Client::with_connector(PinnedConnector {
+        addr: addr,
+        ssl:  Openssl::default(),
+    });
+    client.set_redirect_policy(RedirectPolicy::FollowNone);
+    client.set_read_timeout(Some(Duration::from_secs(POST_TIMEOUT)));
+    client.set_write_timeout(Some(Duration::from_secs(POST_TIMEOUT)));
+    match client.post(&url[..]).header(ContentType::json()).body(&body[..]).send() {
+        Ok(mut res) => {
+            if !res.status.is_success() {
+                return Err(format!("{}", res.status));
+            }
+            let mut out = String::new();
+            res.read_to_string(&mut out).ok();
+            Ok(out)
+        },
+        Err(e) => Err(format!("{}", e)),
+    }
+}
+
+// deliver to callbacks for the given users (or all callbacks, if None), and
+// to any that are due a retry
+fn deliver(db: &Db, retries: &mut HashMap<i64,Retry>, users: Option<&HashSet<i64>>) -> Result<(),DbError> {
+    let now = time::get_time().sec;
+
+    try!(db.expire_push_callbacks(now));
+
+    let callbacks = try!(db.get_push_callbacks());
+
+    // forget retry state for callbacks that have expired or been replaced
+    let gone: Vec<i64> = retries.keys().filter(|id| !callbacks.iter().any(|cb| cb.id == **id)).cloned().collect();
+    for id in gone.iter() {
+        retries.remove(id);
+    }
+
+    for cb in callbacks.iter() {
+        if let Some(u) = users {
+            if !u.contains(&cb.userid) && !retries.contains_key(&cb.id) {
+                continue;
+            }
+        }
+
+        let states = try!(db.get_states(cb.userid));
+        if states == cb.states {
+            continue;
+        }
+
+        if let Some(r) = retries.get(&cb.id) {
+            if now < r.next_attempt {
+                continue;
+            }
+        }
+
+        match post(&cb.url, &states.to_json().to_string()) {
+            Ok(_) => {
+                try!(db.set_push_callback_states(cb.id, &states));
+                retries.remove(&cb.id);
+            },
+            Err(e) => {
+                let failures = match retries.get(&cb.id) {
+                    Some(r) => r.failures + 1,
+                    None    => 1,
+                };
+
+                if failures >= MAX_FAILURES {
+                    info!("push callback {} failed {} times, removing: {}", cb.url, failures, e);
+                    try!(db.remove_push_callback(cb.id));
+                    retries.remove(&cb.id);
+                    continue;
+                }
+
+                let backoff = cmp::min(1 << failures, MAX_BACKOFF);
+                info!("push callback {} failed, retrying in {}s: {}", cb.url, backoff, e);
+                retries.insert(cb.id, Retry {
+                    failures:     failures,
+                    next_attempt: now + backoff,
+                });
+            },
+        }
+    }
+
+    Ok(())
+}
+
+pub fn start(db: Db) {
+    let changes = notify::subscribe();
+
+    thread::spawn(move || {
+        let mut retries: HashMap<i64,Retry> = HashMap::new();
+
+        // check everything on the first pass, in case states changed while we
+        // weren't running
+        let mut full = true;
+
+        loop {
+            let mut users: HashSet<i64> = HashSet::new();
+            match changes.recv_timeout(Duration::from_secs(RETRY_INTERVAL)) {
+                Ok(c) => {
+                    users.insert(c.userid);
+                    users.extend(changes.try_iter().map(|c| c.userid));
+                },
+                Err(RecvTimeoutError::Timeout)      => (),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            match deliver(&db, &mut retries, if full { None } else { Some(&users) }) {
+                Ok(_)  => full = false,
+                Err(e) => {
+                    error!("push: {}", e);
+                    full = true;
+                },
+            }
+        }
+    });
+}
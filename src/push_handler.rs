@@ -0,0 +1,91 @@
+use std::collections::BTreeMap;
+use std::cmp;
+
+use hyper::server::Request;
+use hyper::status::StatusCode;
+
+use rustc_serialize::json::{Json,ToJson};
+
+use uuid::Uuid;
+
+use time;
+
+use http_handler::StatusBody;
+use push;
+
+use pool::DbPool;
+
+// callbacks live for at most this many seconds before they must be registered again
+const MAX_EXPIRY: i64 = 7*24*60*60;
+
+const DATE_FORMAT: &'static str = "%Y-%m-%dT%H:%M:%SZ";
+
+fn bad_request(msg: &str) -> StatusBody {
+    StatusBody::new(StatusCode::BadRequest, Some(msg.to_string().into_bytes()))
+}
+
+// register a push callback. the request body is
+//   { "url": "http://...", "expires": "2015-06-12T00:00:00Z" }
+// with expires optional. before the callback is stored the url is sent
+//   { "verificationCode": "..." }
+// and must respond with the code as the response body
+pub fn handler(mut req: Request, pool: &DbPool) -> StatusBody {
+    let j = match Json::from_reader(&mut req) {
+        Ok(j)  => j,
+        Err(e) => return bad_request(&e.to_string()),
+    };
+
+    let url = match j.find("url").and_then(|u| u.as_string()) {
+        Some(u) if u.starts_with("http://") || u.starts_with("https://") => u.to_string(),
+        _ => return bad_request("url: must be a http or https url"),
+    };
+
+    let now = time::get_time().sec;
+
+    let expires = match j.find("expires") {
+        None => now + MAX_EXPIRY,
+        Some(e) => match e.as_string().and_then(|e| time::strptime(e, DATE_FORMAT).ok()) {
+            Some(tm) => cmp::min(tm.to_timespec().sec, now + MAX_EXPIRY),
+            None     => return bad_request("expires: must be a UTC date"),
+        },
+    };
+    if expires <= now {
+        return bad_request("expires: must be in the future");
+    }
+
+    let userid = 1; // XXX get userid from auth
+
+    let code = Uuid::new_v4().to_hyphenated_string();
+    let mut verification = BTreeMap::new();
+    verification.insert("verificationCode".to_string(), code.clone());
+
+    match push::post(&url, &verification.to_json().to_string()) {
+        Ok(ref body) if body.trim() == code => (),
+        Ok(_) => {
+            info!("push callback {} didn't return verification code", url);
+            return bad_request("url: verification failed");
+        },
+        Err(e) => {
+            info!("push callback {} verification failed: {}", url, e);
+            return bad_request("url: verification failed");
+        },
+    }
+
+    let db = match pool.get() {
+        Ok(db) => db,
+        Err(e) => return StatusBody::new(StatusCode::ServiceUnavailable, Some(format!("{}", e).into_bytes())),
+    };
+
+    let id = match db.set_push_callback(userid, &url, expires) {
+        Ok(id) => id,
+        Err(e) => return StatusBody::new(StatusCode::InternalServerError, Some(format!("{}", e).into_bytes())),
+    };
+
+    info!("registered push callback {} for {}", id, url);
+
+    let mut out = BTreeMap::new();
+    out.insert("id".to_string(), id.to_string());
+    out.insert("expires".to_string(), time::strftime(DATE_FORMAT, &time::at_utc(time::Timespec::new(expires, 0))).unwrap());
+
+    StatusBody::new(StatusCode::Ok, Some(out.to_json().to_string().into_bytes()))
+}
@@ -13,7 +13,7 @@ pub trait RecordHandler<R: Record> {
     fn set_records(&self, args: &SetRequestArgs<R>)               -> Result<SetResponseArgs<R>,MethodError>;
 }
 
-impl<R: Record> RecordHandler<R> for RequestContext where R: RecordType {
+impl<'a, R: Record> RecordHandler<R> for RequestContext<'a> where R: RecordType {
     fn get_records(&self, args: &GetRequestArgs<R>) -> Result<GetResponseArgs<R>,MethodError> {
         let (records, state): (Vec<R>, String) = try!(self.db.transaction(|| {
             Ok((
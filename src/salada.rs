@@ -9,18 +9,62 @@ extern crate uuid;
 #[macro_use]
 extern crate log;
 
+#[macro_use]
+extern crate lazy_static;
+
 mod logger;
 mod db;
 mod http_handler;
 mod jmap_handler;
 mod upload_handler;
 mod static_handler;
+mod eventsource_handler;
+mod push_handler;
+mod push;
+mod ical_handler;
+mod vcard_handler;
 mod util;
 mod record;
+mod notify;
+mod pool;
+mod storage;
+mod sqlite;
+mod tz;
+
+use std::env;
+use std::path::PathBuf;
+
+use db::Db;
+use sqlite::DbLocation;
+
+const DB_POOL_SIZE: usize = 8;
+
+// threads for ordinary requests. event streams get their own on top of these
+const HTTP_THREADS: usize = 8;
 
 fn main() {
     logger::init().unwrap();
 
+    // salada [dbfile|:memory:]
+    let location = match env::args().nth(1) {
+        None                           => DbLocation::File(PathBuf::from("db.sqlite")),
+        Some(ref a) if a == ":memory:" => DbLocation::memory(),
+        Some(a)                        => DbLocation::File(PathBuf::from(a)),
+    };
+
+    info!("Using database {}", match location {
+        DbLocation::File(ref p) => p.display().to_string(),
+        DbLocation::Memory(_)   => "in memory".to_string(),
+    });
+
+    let pool_location = location.clone();
+    let pool = pool::DbPool::new(DB_POOL_SIZE, move || Db::open(&pool_location)).unwrap();
+
+    match Db::open(&location) {
+        Ok(db) => push::start(db),
+        Err(e) => error!("push: couldn't open db, callbacks disabled: {}", e),
+    }
+
     info!("Listening on http://127.0.0.1:3000/jmap");
-    hyper::Server::http("127.0.0.1:3000").unwrap().handle(http_handler::handler).unwrap();
+    hyper::Server::http("127.0.0.1:3000").unwrap().handle_threads(http_handler::HttpHandler::new(pool), HTTP_THREADS + eventsource_handler::MAX_STREAMS).unwrap();
 }
@@ -0,0 +1,382 @@
+use rusqlite::{SqliteConnection, SqliteError};
+use rusqlite::{SQLITE_OPEN_READ_WRITE, SQLITE_OPEN_CREATE, SQLITE_OPEN_URI};
+use rusqlite::types::{ToSql, FromSql};
+use rustc_serialize::json::{Json, ToJson};
+use std::collections::BTreeMap;
+use std::convert::From;
+use std::path::{Path, PathBuf};
+
+use uuid::Uuid;
+
+use db::{DbError, PushCallback};
+use db::DbError::*;
+use storage::Storage;
+
+const VERSION: u32 = 2;
+
+const CREATE_SQL: [&'static str; 6] = [
+r###"
+CREATE TABLE records (
+    rowid       INTEGER PRIMARY KEY,
+    id          TEXT NOT NULL,
+    userid      INTEGER NOT NULL,
+    type        INTEGER NOT NULL,
+    modseq      INTEGER NOT NULL,
+    deleted     INTEGER NOT NULL DEFAULT 0,
+    json        TEXT NOT NULL,
+    UNIQUE( id, userid )
+);
+"###,
+r###"
+CREATE INDEX idx_record_id_userid          ON records ( id, userid );
+"###,
+r###"
+CREATE INDEX idx_record_userid_type        ON records ( userid, type );
+"###,
+r###"
+CREATE INDEX idx_record_userid_type_modseq ON records ( userid, type, modseq );
+"###,
+r###"
+CREATE TABLE modseq (
+    userid      INTEGER NOT NULL,
+    type        INTEGER NOT NULL,
+    modseq      INTEGER NOT NULL,
+    low_modseq  INTEGER NOT NULL,
+    UNIQUE( userid, type )
+);
+"###,
+r###"
+CREATE INDEX idx_userid_type ON modseq ( userid, type );
+"###,
+];
+
+// UPGRADE_SQL[n] takes the database from version n+1 to n+2
+const UPGRADE_SQL: [&'static str; 1] = [
+// v2
+r###"
+CREATE TABLE push_callbacks (
+    rowid       INTEGER PRIMARY KEY,
+    userid      INTEGER NOT NULL,
+    url         TEXT NOT NULL,
+    expires     INTEGER NOT NULL,
+    states      TEXT NOT NULL,
+    UNIQUE( userid, url )
+);
+"###,
+];
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum DbLocation {
+    File(PathBuf),
+
+    // a named in-memory database. connections opened with the same location
+    // share its data through sqlite's shared cache, so the name is unique to
+    // each location made by DbLocation::memory(). shared cache locks are
+    // per-table and a conflict fails immediately with SQLITE_LOCKED rather
+    // than waiting out busy_timeout, so concurrent writers in memory mode
+    // can get "database table is locked" errors. fine for tests, which is
+    // what it's for
+    Memory(String),
+}
+
+impl DbLocation {
+    pub fn memory() -> DbLocation {
+        DbLocation::Memory(Uuid::new_v4().to_hyphenated_string())
+    }
+}
+
+impl From<SqliteError> for DbError {
+    fn from(e: SqliteError) -> DbError {
+        InternalError(format!("sqlite: {}", e))
+    }
+}
+
+#[derive(Debug)]
+pub struct SqliteStorage {
+    conn: SqliteConnection,
+}
+
+impl SqliteStorage {
+    pub fn open(location: &DbLocation) -> Result<SqliteStorage,DbError> {
+        let conn = match *location {
+            DbLocation::File(ref path)   => try!(SqliteConnection::open(path)),
+            DbLocation::Memory(ref name) => {
+                let uri = format!("file:salada-{}?mode=memory&cache=shared", name);
+                try!(SqliteConnection::open_with_flags(&Path::new(&uri), SQLITE_OPEN_READ_WRITE | SQLITE_OPEN_CREATE | SQLITE_OPEN_URI))
+            },
+        };
+        let s = SqliteStorage {
+            conn: conn,
+        };
+
+        // other connections may hold a lock; wait for them rather than failing immediately
+        try!(s.exec_value::<i64>("PRAGMA busy_timeout = 5000", &[]));
+
+        try!(s.exec("BEGIN DEFERRED", &[]));
+        match s.upgrade() {
+            Ok(_)  => try!(s.exec("COMMIT", &[])),
+            Err(e) => {
+                try!(s.exec("ROLLBACK", &[]));
+                return Err(e);
+            },
+        };
+
+        Ok(s)
+    }
+
+    fn exec(&self, sql: &str, params: &[&ToSql]) -> Result<usize,DbError> {
+        let mut stmt = try!(self.conn.prepare(sql));
+        Ok(try!(stmt.execute(params)) as usize)
+    }
+
+    fn exec_value<T>(&self, sql: &str, params: &[&ToSql]) -> Result<Option<T>,DbError> where T: FromSql {
+        let mut stmt = try!(self.conn.prepare(sql));
+        let mut res = try!(stmt.query(params));
+
+        match res.next() {
+            None       => Ok(None),
+            Some(next) =>
+                match next {
+                    Err(e)   => Err(InternalError(format!("sqlite: {}", e))),
+                    Ok(next) => {
+                        let v: T = next.get(0);
+                        Ok(Some(v))
+                    },
+            }
+        }
+    }
+
+    fn version(&self) -> Result<u32,DbError> {
+        let v = try!(self.exec_value::<i32>("PRAGMA user_version", &[]));
+        match v {
+            Some(v) => Ok(v as u32),
+            None    => Ok(0),
+        }
+    }
+
+    fn set_version(&self, v: u32) -> Result<(),DbError> {
+        try!(self.exec(format!("PRAGMA user_version = {}", v as i32).as_ref(), &[]));
+        Ok(())
+    }
+
+    fn upgrade(&self) -> Result<(),DbError> {
+        let mut ver = try!(self.version());
+        if ver == VERSION { return Ok(()) }
+
+        // written by a newer salada; we don't know how to read it
+        if ver > VERSION {
+            return Err(InternalError(format!("db version {} is newer than supported version {}", ver, VERSION)));
+        }
+
+        // new database
+        if ver == 0 {
+            for sql in CREATE_SQL.iter() {
+                try!(self.exec(sql, &[]));
+            }
+            ver = 1;
+        }
+
+        // existing database, upgrade required
+        for sql in UPGRADE_SQL[(ver-1) as usize..].iter() {
+            try!(self.exec(sql, &[]));
+        }
+
+        try!(self.set_version(VERSION));
+
+        info!("upgraded db to version {}", VERSION);
+
+        Ok(())
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn begin(&self) -> Result<(),DbError> {
+        try!(self.exec("BEGIN DEFERRED", &[]));
+        Ok(())
+    }
+
+    fn begin_exclusive(&self) -> Result<(),DbError> {
+        try!(self.exec("BEGIN EXCLUSIVE", &[]));
+        Ok(())
+    }
+
+    fn commit(&self) -> Result<(),DbError> {
+        try!(self.exec("COMMIT", &[]));
+        Ok(())
+    }
+
+    fn rollback(&self) -> Result<(),DbError> {
+        try!(self.exec("ROLLBACK", &[]));
+        Ok(())
+    }
+
+    fn savepoint(&self) -> Result<(),DbError> {
+        try!(self.exec("SAVEPOINT sp", &[]));
+        Ok(())
+    }
+
+    fn release_savepoint(&self) -> Result<(),DbError> {
+        try!(self.exec("RELEASE sp", &[]));
+        Ok(())
+    }
+
+    fn rollback_savepoint(&self) -> Result<(),DbError> {
+        try!(self.exec("ROLLBACK TO sp", &[]));
+        Ok(())
+    }
+
+    fn get_modseq(&self, userid: i64, rectype: i32) -> Result<Option<i64>,DbError> {
+        let params: Vec<&ToSql> = vec!(&userid, &rectype);
+        self.exec_value::<i64>("SELECT modseq FROM modseq WHERE userid = ? AND type = ?", params.as_ref())
+    }
+
+    fn get_low_modseq(&self, userid: i64, rectype: i32) -> Result<Option<i64>,DbError> {
+        let params: Vec<&ToSql> = vec!(&userid, &rectype);
+        self.exec_value::<i64>("SELECT low_modseq FROM modseq WHERE userid = ? AND type = ?", params.as_ref())
+    }
+
+    fn next_modseq(&self, userid: i64, rectype: i32) -> Result<i64,DbError> {
+        let params: Vec<&ToSql> = vec!(&userid, &rectype);
+        if let 0 = try!(self.exec("UPDATE modseq SET modseq = (modseq+1) WHERE userid = ? AND type = ?", &params)) {
+            try!(self.exec("INSERT INTO modseq ( userid, type, modseq, low_modseq ) VALUES ( ?, ?, 1, 1 )", &params));
+        }
+        Ok(try!(self.get_modseq(userid, rectype)).unwrap_or(0))
+    }
+
+    fn get_records(&self, userid: i64, rectype: i32, ids: Option<&Vec<String>>, since_modseq: Option<i64>) -> Result<Vec<(String,String)>,DbError> {
+        let mut sql = "SELECT id,json FROM records WHERE userid = ? AND type = ? AND deleted = 0".to_string();
+        let mut params: Vec<&ToSql> = vec!(&userid, &rectype);
+
+        let modseq: i64;
+        if let Some(m) = since_modseq {
+            modseq = m;
+            sql.push_str(" AND modseq > ?");
+            params.push(&modseq);
+        }
+
+        if let Some(ref ids) = ids {
+            sql.push_str(" AND id IN ( ");
+
+            let mut i = ids.iter();
+            if let Some(id) = i.next() {
+                sql.push_str("?");
+                params.push(id);
+            }
+            for id in i {
+                sql.push_str(",?");
+                params.push(id);
+            }
+
+            sql.push_str(" )");
+        }
+
+        let mut stmt = try!(self.conn.prepare(sql.as_ref()));
+        let res = try!(stmt.query(params.as_ref()));
+
+        let mut records: Vec<(String,String)> = Vec::new();
+        for row in res {
+            if let Ok(ref r) = row {
+                records.push((r.get::<String>(0), r.get::<String>(1)));
+            }
+        }
+
+        Ok(records)
+    }
+
+    fn count_changes(&self, userid: i64, rectype: i32, since_modseq: i64) -> Result<i64,DbError> {
+        let params: Vec<&ToSql> = vec!(&userid, &rectype, &since_modseq);
+        Ok(try!(self.exec_value::<i64>("SELECT COUNT(*) FROM records WHERE userid = ? AND type = ? AND modseq > ?", params.as_ref())).unwrap_or(0))
+    }
+
+    fn get_changes(&self, userid: i64, rectype: i32, since_modseq: i64, limit: Option<i64>) -> Result<Vec<(String,bool)>,DbError> {
+        let mut sql = "SELECT id,deleted FROM records WHERE userid = ? AND type = ? AND modseq > ?".to_string();
+        let mut params: Vec<&ToSql> = vec!(&userid, &rectype, &since_modseq);
+
+        let max: i64;
+        if let Some(l) = limit {
+            max = l;
+            sql.push_str(" LIMIT ?");
+            params.push(&max);
+        }
+
+        let mut stmt = try!(self.conn.prepare(sql.as_ref()));
+        let res = try!(stmt.query(params.as_ref()));
+
+        let mut changes: Vec<(String,bool)> = Vec::new();
+        for row in res {
+            if let Ok(ref r) = row {
+                changes.push((r.get::<String>(0), r.get::<i64>(1) == 1));
+            }
+        }
+
+        Ok(changes)
+    }
+
+    fn insert_record(&self, userid: i64, rectype: i32, id: &String, json: &String) -> Result<(),DbError> {
+        let params: Vec<&ToSql> = vec!(&userid, &rectype, &userid, &rectype, id, json);
+        try!(self.exec("INSERT INTO records ( userid, type, modseq, id, json ) VALUES ( ?, ?, (SELECT modseq FROM modseq WHERE userid = ? AND type = ?), ?, ?)", &params));
+        Ok(())
+    }
+
+    fn update_record(&self, userid: i64, rectype: i32, id: &String, json: &String) -> Result<(),DbError> {
+        let params: Vec<&ToSql> = vec!(&userid, &rectype, json, &userid, &rectype, id);
+        try!(self.exec("UPDATE records SET modseq = (SELECT modseq FROM modseq WHERE userid = ? AND type = ?), json = ? WHERE userid = ? AND type = ? AND id = ?", &params));
+        Ok(())
+    }
+
+    fn destroy_record(&self, userid: i64, rectype: i32, id: &String) -> Result<(),DbError> {
+        let params: Vec<&ToSql> = vec!(&userid, &rectype, &userid, &rectype, id);
+        try!(self.exec("UPDATE records SET deleted = 1, modseq = (SELECT modseq FROM modseq WHERE userid = ? AND type = ?) WHERE userid = ? AND type = ? AND id = ? AND deleted = 0", &params));
+        Ok(())
+    }
+
+    fn set_push_callback(&self, userid: i64, url: &String, expires: i64, states: &BTreeMap<String,String>) -> Result<i64,DbError> {
+        let json = states.to_json().to_string();
+        let params: Vec<&ToSql> = vec!(&userid, url, &expires, &json);
+        try!(self.exec("INSERT OR REPLACE INTO push_callbacks ( userid, url, expires, states ) VALUES ( ?, ?, ?, ? )", &params));
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    fn get_push_callbacks(&self) -> Result<Vec<PushCallback>,DbError> {
+        let mut stmt = try!(self.conn.prepare("SELECT rowid,userid,url,expires,states FROM push_callbacks"));
+        let res = try!(stmt.query(&[]));
+
+        let mut callbacks: Vec<PushCallback> = Vec::new();
+        for row in res {
+            if let Ok(ref r) = row {
+                let json = try!(Json::from_str((r.get::<String>(4)).as_ref()));
+                let states = match json.as_object() {
+                    Some(o) => o.iter().filter_map(|(k,v)| v.as_string().map(|v| (k.clone(), v.to_string()))).collect(),
+                    None    => BTreeMap::new(),
+                };
+                callbacks.push(PushCallback {
+                    id:      r.get::<i64>(0),
+                    userid:  r.get::<i64>(1),
+                    url:     r.get::<String>(2),
+                    expires: r.get::<i64>(3),
+                    states:  states,
+                });
+            }
+        }
+
+        Ok(callbacks)
+    }
+
+    fn set_push_callback_states(&self, id: i64, states: &BTreeMap<String,String>) -> Result<(),DbError> {
+        let json = states.to_json().to_string();
+        let params: Vec<&ToSql> = vec!(&json, &id);
+        try!(self.exec("UPDATE push_callbacks SET states = ? WHERE rowid = ?", &params));
+        Ok(())
+    }
+
+    fn remove_push_callback(&self, id: i64) -> Result<(),DbError> {
+        let params: Vec<&ToSql> = vec!(&id);
+        try!(self.exec("DELETE FROM push_callbacks WHERE rowid = ?", &params));
+        Ok(())
+    }
+
+    fn expire_push_callbacks(&self, now: i64) -> Result<usize,DbError> {
+        let params: Vec<&ToSql> = vec!(&now);
+        self.exec("DELETE FROM push_callbacks WHERE expires <= ?", &params)
+    }
+}
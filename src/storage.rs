@@ -0,0 +1,43 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use db::{DbError, PushCallback};
+
+// the raw storage operations Db is built on. records are handled as json
+// strings, keyed by userid, record type and id; Db does the conversion to and
+// from jmap records and the state/transaction bookkeeping
+pub trait Storage: Send + fmt::Debug {
+    // transactions. savepoints are only used inside an open transaction, and
+    // may nest
+    fn begin(&self)              -> Result<(),DbError>;
+    fn begin_exclusive(&self)    -> Result<(),DbError>;
+    fn commit(&self)             -> Result<(),DbError>;
+    fn rollback(&self)           -> Result<(),DbError>;
+    fn savepoint(&self)          -> Result<(),DbError>;
+    fn release_savepoint(&self)  -> Result<(),DbError>;
+    fn rollback_savepoint(&self) -> Result<(),DbError>;
+
+    // per-type modseq. None if the type has never been modified
+    fn get_modseq(&self, userid: i64, rectype: i32)     -> Result<Option<i64>,DbError>;
+    fn get_low_modseq(&self, userid: i64, rectype: i32) -> Result<Option<i64>,DbError>;
+    fn next_modseq(&self, userid: i64, rectype: i32)    -> Result<i64,DbError>;
+
+    // undeleted records as (id, json), optionally limited to the given ids
+    // and/or those modified after since_modseq
+    fn get_records(&self, userid: i64, rectype: i32, ids: Option<&Vec<String>>, since_modseq: Option<i64>) -> Result<Vec<(String,String)>,DbError>;
+
+    // records modified after since_modseq as (id, deleted)
+    fn count_changes(&self, userid: i64, rectype: i32, since_modseq: i64)                    -> Result<i64,DbError>;
+    fn get_changes(&self, userid: i64, rectype: i32, since_modseq: i64, limit: Option<i64>) -> Result<Vec<(String,bool)>,DbError>;
+
+    // record modifications take the current modseq for their type
+    fn insert_record(&self, userid: i64, rectype: i32, id: &String, json: &String) -> Result<(),DbError>;
+    fn update_record(&self, userid: i64, rectype: i32, id: &String, json: &String) -> Result<(),DbError>;
+    fn destroy_record(&self, userid: i64, rectype: i32, id: &String)               -> Result<(),DbError>;
+
+    fn set_push_callback(&self, userid: i64, url: &String, expires: i64, states: &BTreeMap<String,String>) -> Result<i64,DbError>;
+    fn get_push_callbacks(&self)                                                   -> Result<Vec<PushCallback>,DbError>;
+    fn set_push_callback_states(&self, id: i64, states: &BTreeMap<String,String>) -> Result<(),DbError>;
+    fn remove_push_callback(&self, id: i64)                                        -> Result<(),DbError>;
+    fn expire_push_callbacks(&self, now: i64)                                      -> Result<usize,DbError>;
+}
@@ -0,0 +1,241 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+// compiled zone files, as shipped with most unixes. we only use the POSIX TZ
+// rule at the end of each file, which describes the zone as it is now, so
+// historical changes to a zone aren't represented
+const ZONEINFO_DIR: &'static str = "/usr/share/zoneinfo";
+
+// the nth (5 = last) weekday (0 = Sunday) of a month, at some seconds past
+// midnight local time
+#[derive(Clone, PartialEq, Debug)]
+struct Rule {
+    month:   u32,
+    week:    u32,
+    weekday: u32,
+    time:    i64,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+struct Observance {
+    name:   String,
+    offset: i64, // seconds east of UTC
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct TimeZone {
+    pub id:   String,
+    standard: Observance,
+
+    // daylight time, and the rules for when it starts and ends
+    daylight: Option<(Observance, Rule, Rule)>,
+}
+
+// day of the week, 0 = Sunday
+fn day_of_week(year: i64, month: u32, day: u32) -> u32 {
+    let t = [0, 3, 2, 5, 0, 3, 5, 1, 4, 6, 2, 4];
+    let y = if month < 3 { year - 1 } else { year };
+    ((y + y/4 - y/100 + y/400 + t[(month-1) as usize] + day as i64) % 7) as u32
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if (year % 4 == 0 && year % 100 != 0) || year % 400 == 0 => 29,
+        2                                                          => 28,
+        4 | 6 | 9 | 11                                             => 30,
+        _                                                          => 31,
+    }
+}
+
+impl Rule {
+    // day of the month the rule falls on in the given year
+    fn day(&self, year: i64) -> u32 {
+        let first = day_of_week(year, self.month, 1);
+        let mut day = 1 + (self.weekday + 7 - first) % 7 + (self.week - 1) * 7;
+        while day > days_in_month(year, self.month) {
+            day -= 7;
+        }
+        day
+    }
+
+    fn rrule(&self) -> String {
+        let days = ["SU", "MO", "TU", "WE", "TH", "FR", "SA"];
+        let week = match self.week {
+            5 => "-1".to_string(),
+            w => w.to_string(),
+        };
+        format!("RRULE:FREQ=YEARLY;BYMONTH={};BYDAY={}{}", self.month, week, days[self.weekday as usize])
+    }
+
+    fn dtstart(&self) -> String {
+        format!("DTSTART:1970{:02}{:02}T{:02}{:02}{:02}", self.month, self.day(1970), self.time / 3600, self.time / 60 % 60, self.time % 60)
+    }
+}
+
+// "+1000", "-0330", "+053328"
+fn ical_offset(offset: i64) -> String {
+    let (sign, o) = if offset < 0 { ('-', -offset) } else { ('+', offset) };
+    match o % 60 {
+        0 => format!("{}{:02}{:02}", sign, o / 3600, o / 60 % 60),
+        s => format!("{}{:02}{:02}{:02}", sign, o / 3600, o / 60 % 60, s),
+    }
+}
+
+// "AEST" or "<+03>"
+fn parse_name(s: &str) -> Option<(String,&str)> {
+    if s.starts_with('<') {
+        return s.find('>').map(|n| (s[1..n].to_string(), &s[n+1..]));
+    }
+    let n = s.find(|c: char| !c.is_alphabetic()).unwrap_or(s.len());
+    match n {
+        0 => None,
+        _ => Some((s[..n].to_string(), &s[n..])),
+    }
+}
+
+// "[+-]hh[:mm[:ss]]" as seconds
+fn parse_time(s: &str) -> Option<(i64,&str)> {
+    let (sign, s) = match s.chars().next() {
+        Some('-') => (-1, &s[1..]),
+        Some('+') => (1, &s[1..]),
+        _         => (1, s),
+    };
+    let n = s.find(|c: char| !c.is_digit(10) && c != ':').unwrap_or(s.len());
+    let mut secs = 0;
+    let mut unit = 3600;
+    for p in s[..n].split(':') {
+        if unit == 0 { return None }
+        secs += match p.parse::<i64>() {
+            Ok(v)  => v * unit,
+            Err(_) => return None,
+        };
+        unit /= 60;
+    }
+    Some((sign * secs, &s[n..]))
+}
+
+// "Mm.w.d[/time]". the Julian day forms are rare and not supported
+fn parse_rule(s: &str) -> Option<(Rule,&str)> {
+    if !s.starts_with('M') { return None }
+    let n = s.find(|c| c == '/' || c == ',').unwrap_or(s.len());
+    let parts: Vec<u32> = s[1..n].split('.').filter_map(|p| p.parse::<u32>().ok()).collect();
+    if parts.len() != 3 || parts[0] < 1 || parts[0] > 12 || parts[1] < 1 || parts[1] > 5 || parts[2] > 6 {
+        return None;
+    }
+
+    let (time, rest) = match s[n..].starts_with('/') {
+        true  => match parse_time(&s[n+1..]) {
+            Some(t) => t,
+            None    => return None,
+        },
+        false => (2*3600, &s[n..]),
+    };
+    // transitions outside the day would move the date; not worth handling
+    if time < 0 || time >= 24*3600 { return None }
+
+    Some((Rule { month: parts[0], week: parts[1], weekday: parts[2], time: time }, rest))
+}
+
+// a POSIX TZ string, eg "AEST-10AEDT,M10.1.0,M4.1.0/3". offsets in these
+// are west of UTC, so the sign is flipped
+fn parse_posix(id: &str, s: &str) -> Option<TimeZone> {
+    let (std_name, s) = match parse_name(s) { Some(v) => v, None => return None };
+    let (std_offset, s) = match parse_time(s) { Some(v) => v, None => return None };
+
+    let standard = Observance { name: std_name, offset: -std_offset };
+
+    if s.len() == 0 {
+        return Some(TimeZone { id: id.to_string(), standard: standard, daylight: None });
+    }
+
+    let (dst_name, s) = match parse_name(s) { Some(v) => v, None => return None };
+    let (dst_offset, s) = match s.starts_with(',') {
+        true  => (standard.offset + 3600, s),
+        false => match parse_time(s) {
+            Some((o, s)) => (-o, s),
+            None         => return None,
+        },
+    };
+
+    if !s.starts_with(',') { return None }
+    let (start, s) = match parse_rule(&s[1..]) { Some(v) => v, None => return None };
+    if !s.starts_with(',') { return None }
+    let (end, s) = match parse_rule(&s[1..]) { Some(v) => v, None => return None };
+    if s.len() > 0 { return None }
+
+    Some(TimeZone {
+        id:       id.to_string(),
+        standard: standard,
+        daylight: Some((Observance { name: dst_name, offset: dst_offset }, start, end)),
+    })
+}
+
+// zone names are paths under ZONEINFO_DIR; don't let them go anywhere else
+fn valid_id(id: &str) -> bool {
+    id.len() > 0 &&
+        id.chars().all(|c| c.is_alphanumeric() || c == '/' || c == '_' || c == '-' || c == '+') &&
+        id.split('/').all(|p| p.len() > 0 && p != "." && p != "..")
+}
+
+impl TimeZone {
+    // load a zone by its Olson name, eg "Australia/Melbourne". None if the
+    // zone doesn't exist or its rule can't be represented
+    pub fn load(id: &str) -> Option<TimeZone> {
+        if !valid_id(id) { return None }
+
+        let mut buf = vec!();
+        if let Err(_) = File::open(Path::new(ZONEINFO_DIR).join(id)).and_then(|mut f| f.read_to_end(&mut buf)) {
+            return None;
+        }
+
+        // version 2 and later files end with "\n<TZ string>\n"
+        if buf.len() < 6 || &buf[0..4] != b"TZif" || buf[4] < b'2' || buf[buf.len()-1] != b'\n' {
+            return None;
+        }
+        let end = buf.len() - 1;
+        let start = match buf[..end].iter().rposition(|b| *b == b'\n') {
+            Some(n) => n + 1,
+            None    => return None,
+        };
+
+        match String::from_utf8(buf[start..end].to_vec()) {
+            Ok(ref s) if s.len() > 0 => parse_posix(id, s),
+            _                        => None,
+        }
+    }
+
+    // the lines of a VTIMEZONE component for this zone
+    pub fn vtimezone(&self) -> Vec<String> {
+        let mut lines = vec!(
+            "BEGIN:VTIMEZONE".to_string(),
+            format!("TZID:{}", self.id),
+        );
+
+        match self.daylight {
+            None => {
+                let o = ical_offset(self.standard.offset);
+                lines.push("BEGIN:STANDARD".to_string());
+                lines.push("DTSTART:19700101T000000".to_string());
+                lines.push(format!("TZOFFSETFROM:{}", o));
+                lines.push(format!("TZOFFSETTO:{}", o));
+                lines.push(format!("TZNAME:{}", self.standard.name));
+                lines.push("END:STANDARD".to_string());
+            },
+            Some((ref dst, ref start, ref end)) => {
+                for &(kind, ref to, ref from, rule) in [("STANDARD", &self.standard, dst, end), ("DAYLIGHT", dst, &self.standard, start)].iter() {
+                    lines.push(format!("BEGIN:{}", kind));
+                    lines.push(rule.dtstart());
+                    lines.push(rule.rrule());
+                    lines.push(format!("TZOFFSETFROM:{}", ical_offset(from.offset)));
+                    lines.push(format!("TZOFFSETTO:{}", ical_offset(to.offset)));
+                    lines.push(format!("TZNAME:{}", to.name));
+                    lines.push(format!("END:{}", kind));
+                }
+            },
+        }
+
+        lines.push("END:VTIMEZONE".to_string());
+        lines
+    }
+}
@@ -1,7 +1,244 @@
+use std::str;
+
 use db::Db;
 
 #[derive(Debug)]
-pub struct RequestContext {
+pub struct RequestContext<'a> {
     pub userid: i64, // XXX would prefer u64 but sqlite integer type
-    pub db: Db,
+    pub db: &'a Db,
+}
+
+// decode %XX escapes in a url component
+fn percent_decode(s: &str) -> String {
+    let b = s.as_bytes();
+    let mut out: Vec<u8> = Vec::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        if b[i] == b'%' && i+2 < b.len() {
+            if let Some(v) = str::from_utf8(&b[i+1..i+3]).ok().and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                out.push(v);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(if b[i] == b'+' { b' ' } else { b[i] });
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// the path part of a request path, without any query string or fragment
+pub fn path_only(path: &String) -> &str {
+    match path.find(|c| c == '?' || c == '#') {
+        Some(n) => &path[..n],
+        None    => path,
+    }
+}
+
+// value of a query string parameter in a request path
+pub fn query_param(path: &String, name: &str) -> Option<String> {
+    let query = match path.find('?') {
+        Some(n) => &path[n+1..],
+        None    => return None,
+    };
+    let query = match query.find('#') {
+        Some(n) => &query[..n],
+        None    => query,
+    };
+
+    for param in query.split('&') {
+        let mut kv = param.splitn(2, '=');
+        if kv.next() == Some(name) {
+            return Some(percent_decode(kv.next().unwrap_or("")));
+        }
+    }
+
+    None
+}
+
+// escape a TEXT value for an iCalendar or vCard content line
+pub fn escape_text(s: &str) -> String {
+    s.replace("\\", "\\\\").replace(";", "\\;").replace(",", "\\,").replace("\r\n", "\\n").replace("\n", "\\n")
+}
+
+// fold an iCalendar or vCard content line at 75 octets, without splitting a
+// character, and terminate it with CRLF
+pub fn fold_line(line: &str) -> String {
+    let mut out = String::with_capacity(line.len() + line.len()/74*3 + 2);
+    let mut len = 0;
+    for c in line.chars() {
+        if len + c.len_utf8() > 75 {
+            out.push_str("\r\n ");
+            len = 1;
+        }
+        out.push(c);
+        len += c.len_utf8();
+    }
+    out.push_str("\r\n");
+    out
+}
+
+// undo escape_text
+pub fn unescape_text(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') | Some('N') => out.push('\n'),
+                Some(c)               => out.push(c),
+                None                  => (),
+            },
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+// split a value on an unescaped separator, unescaping each part
+pub fn split_escaped(s: &str, sep: char) -> Vec<String> {
+    let mut parts = vec!();
+    let mut start = 0;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped  => escaped = false,
+            '\\'          => escaped = true,
+            _ if c == sep => {
+                parts.push(unescape_text(&s[start..i]));
+                start = i+1;
+            },
+            _             => (),
+        }
+    }
+    parts.push(unescape_text(&s[start..]));
+    parts
+}
+
+// split on a separator that isn't inside double quotes
+fn split_unquoted(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = vec!();
+    let mut start = 0;
+    let mut quoted = false;
+    for (i, c) in s.char_indices() {
+        if c == '"' { quoted = !quoted }
+        if c == sep && !quoted {
+            parts.push(&s[start..i]);
+            start = i+1;
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+// one line of an iCalendar or vCard file. names are uppercased, the value is
+// left escaped
+#[derive(Debug)]
+pub struct ContentLine {
+    pub name:   String,
+    pub params: Vec<(String,String)>,
+    pub value:  String,
+}
+
+impl ContentLine {
+    // parse "NAME;PARAM=value;...:value", dropping any group prefix
+    // ("item1.EMAIL"). bare parameters ("HOME") are from vCard 2.1, where
+    // they're types
+    fn parse(line: &str) -> Option<ContentLine> {
+        let mut quoted = false;
+        let colon = match line.char_indices().find(|&(_, c)| {
+            if c == '"' { quoted = !quoted }
+            c == ':' && !quoted
+        }) {
+            Some((n, _)) => n,
+            None         => return None,
+        };
+
+        let parts = split_unquoted(&line[..colon], ';');
+        let name = parts[0].rsplit('.').next().unwrap_or("").to_uppercase();
+        if name.len() == 0 { return None }
+
+        let params = parts[1..].iter().map(|p| {
+            let mut kv = p.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) => (k.to_uppercase(), v.to_string()),
+                (Some(t), None)    => ("TYPE".to_string(), t.to_string()),
+                _                  => (String::new(), String::new()),
+            }
+        }).collect();
+
+        Some(ContentLine {
+            name:   name,
+            params: params,
+            value:  line[colon+1..].to_string(),
+        })
+    }
+
+    // first value of a parameter, unquoted
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.iter().find(|&&(ref k, _)| k == name).map(|&(_, ref v)| v.trim_matches('"'))
+    }
+
+    // all TYPE parameter values, uppercased
+    pub fn types(&self) -> Vec<String> {
+        self.params.iter()
+            .filter(|&&(ref k, _)| k == "TYPE")
+            .flat_map(|&(_, ref v)| split_unquoted(v, ',').into_iter().map(|t| t.trim_matches('"').to_uppercase()).collect::<Vec<_>>().into_iter())
+            .collect()
+    }
+}
+
+// a BEGIN/END block, eg VCARD or VEVENT, with the lines and blocks inside it
+#[derive(Debug)]
+pub struct Component {
+    pub name:       String,
+    pub lines:      Vec<ContentLine>,
+    pub components: Vec<Component>,
+}
+
+impl Component {
+    pub fn line(&self, name: &str) -> Option<&ContentLine> {
+        self.lines.iter().find(|l| l.name == name)
+    }
+}
+
+// parse an iCalendar or vCard file into its top-level components, unfolding
+// lines. anything outside a component, and unterminated components, are
+// dropped
+pub fn parse_components(data: &str) -> Vec<Component> {
+    let mut lines: Vec<String> = vec!();
+    for l in data.split('\n').map(|l| l.trim_right_matches('\r')) {
+        if l.starts_with(' ') || l.starts_with('\t') {
+            if let Some(last) = lines.last_mut() {
+                last.push_str(&l[1..]);
+                continue;
+            }
+        }
+        lines.push(l.to_string());
+    }
+
+    let mut top = vec!();
+    let mut stack: Vec<Component> = vec!();
+    for l in lines.iter().filter_map(|l| ContentLine::parse(l)) {
+        match &l.name[..] {
+            "BEGIN" => stack.push(Component {
+                name:       l.value.to_uppercase(),
+                lines:      vec!(),
+                components: vec!(),
+            }),
+            "END" => {
+                if stack.last().map(|c| c.name == l.value.to_uppercase()).unwrap_or(false) {
+                    let c = stack.pop().unwrap();
+                    match stack.last_mut() {
+                        Some(parent) => parent.components.push(c),
+                        None         => top.push(c),
+                    }
+                }
+            },
+            _ => if let Some(c) = stack.last_mut() {
+                c.lines.push(l);
+            },
+        }
+    }
+    top
 }
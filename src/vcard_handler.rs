@@ -0,0 +1,447 @@
+use std::io::Read;
+use std::collections::BTreeMap;
+
+use hyper::server::{Request, Response};
+use hyper::status::StatusCode;
+use hyper::header;
+
+use rustc_serialize::json::{Json, ToJson};
+
+use jmap::{Contact, ContactGroup};
+use jmap::record::Record;
+use jmap::parse::FromJson;
+
+use http_handler::StatusBody;
+
+use pool::DbPool;
+use db::{Db, DbError};
+use util::{query_param, escape_text, unescape_text, split_escaped, fold_line, parse_components, Component};
+
+// contact list types and the vCard TYPE parameter for them
+const EMAIL_TYPES: [(&'static str, &'static str); 2] = [
+    ("personal", "HOME"),
+    ("work",     "WORK"),
+];
+
+const PHONE_TYPES: [(&'static str, &'static str); 5] = [
+    ("home",   "HOME"),
+    ("work",   "WORK"),
+    ("mobile", "CELL"),
+    ("fax",    "FAX"),
+    ("pager",  "PAGER"),
+];
+
+const ADDRESS_TYPES: [(&'static str, &'static str); 4] = [
+    ("home",    "HOME"),
+    ("work",    "WORK"),
+    ("billing", "X-BILLING"),
+    ("postal",  "POSTAL"),
+];
+
+fn str_prop<'a>(j: &'a Json, name: &str) -> Option<&'a str> {
+    match j.find(name).and_then(|v| v.as_string()) {
+        Some("") => None,
+        v        => v,
+    }
+}
+
+fn vcard_type(types: &[(&'static str, &'static str)], t: Option<&str>) -> Option<&'static str> {
+    types.iter().find(|&&(jt, _)| Some(jt) == t).map(|&(_, vt)| vt)
+}
+
+fn jmap_type(types: &[(&'static str, &'static str)], params: &Vec<String>) -> &'static str {
+    types.iter().find(|&&(_, vt)| params.iter().any(|p| p == vt)).map(|&(jt, _)| jt).unwrap_or("other")
+}
+
+// a vCard property line with TYPE parameters
+fn typed_line(name: &str, extra: Option<&str>, t: Option<&str>, pref: bool, value: &str) -> String {
+    let mut types: Vec<&str> = vec!();
+    if let Some(e) = extra { types.push(e) }
+    if let Some(t) = t { types.push(t) }
+    if pref { types.push("PREF") }
+    match types.len() {
+        0 => format!("{}:{}", name, value),
+        _ => format!("{};TYPE={}:{}", name, types.join(","), value),
+    }
+}
+
+fn structured(parts: &[Option<&str>]) -> String {
+    parts.iter().map(|p| escape_text(p.unwrap_or(""))).collect::<Vec<_>>().join(";")
+}
+
+fn write_contact(out: &mut String, c: &Json, categories: &Vec<String>) {
+    let mut lines: Vec<String> = vec!(
+        "BEGIN:VCARD".to_string(),
+        "VERSION:3.0".to_string(),
+    );
+
+    if let Some(id) = str_prop(c, "id") {
+        lines.push(format!("UID:{}", escape_text(id)));
+    }
+
+    let names = ["prefix", "firstName", "lastName", "suffix"].iter().filter_map(|p| str_prop(c, p)).collect::<Vec<_>>();
+    let full_name = match names.len() {
+        0 => str_prop(c, "company").unwrap_or("").to_string(),
+        _ => names.join(" "),
+    };
+    lines.push(format!("FN:{}", escape_text(&full_name)));
+    lines.push(format!("N:{}", structured(&[str_prop(c, "lastName"), str_prop(c, "firstName"), None, str_prop(c, "prefix"), str_prop(c, "suffix")])));
+
+    if let Some(n) = str_prop(c, "nickname") {
+        lines.push(format!("NICKNAME:{}", escape_text(n)));
+    }
+    if str_prop(c, "company").is_some() || str_prop(c, "department").is_some() {
+        lines.push(format!("ORG:{}", structured(&[str_prop(c, "company"), str_prop(c, "department")])));
+    }
+    if let Some(t) = str_prop(c, "jobTitle") {
+        lines.push(format!("TITLE:{}", escape_text(t)));
+    }
+
+    // "0000-00-00" is an unknown date
+    match str_prop(c, "birthday") {
+        Some("0000-00-00") | None => (),
+        Some(d)                   => lines.push(format!("BDAY;VALUE=DATE:{}", d)),
+    }
+    match str_prop(c, "anniversary") {
+        Some("0000-00-00") | None => (),
+        Some(d)                   => lines.push(format!("X-ANNIVERSARY;VALUE=DATE:{}", d)),
+    }
+
+    let list = |name: &str| c.find(name).and_then(|v| v.as_array()).map(|a| a.clone()).unwrap_or(vec!());
+    let is_default = |j: &Json| j.find("isDefault").and_then(|v| v.as_boolean()).unwrap_or(false);
+
+    for e in list("emails").iter() {
+        if let Some(v) = str_prop(e, "value") {
+            lines.push(typed_line("EMAIL", Some("INTERNET"), vcard_type(&EMAIL_TYPES, str_prop(e, "type")), is_default(e), &escape_text(v)));
+        }
+    }
+    for p in list("phones").iter() {
+        if let Some(v) = str_prop(p, "value") {
+            lines.push(typed_line("TEL", None, vcard_type(&PHONE_TYPES, str_prop(p, "type")), is_default(p), &escape_text(v)));
+        }
+    }
+    // XXX only uri entries have a vCard 3.0 property; usernames are dropped
+    for o in list("online").iter() {
+        match (str_prop(o, "type"), str_prop(o, "value")) {
+            (Some("uri"), Some(v)) => lines.push(format!("URL:{}", escape_text(v))),
+            _                      => (),
+        }
+    }
+    for a in list("addresses").iter() {
+        let value = structured(&[None, None, str_prop(a, "street"), str_prop(a, "locality"), str_prop(a, "region"), str_prop(a, "postcode"), str_prop(a, "country")]);
+        lines.push(typed_line("ADR", None, vcard_type(&ADDRESS_TYPES, str_prop(a, "type")), is_default(a), &value));
+    }
+
+    if let Some(n) = str_prop(c, "notes") {
+        lines.push(format!("NOTE:{}", escape_text(n)));
+    }
+
+    // groups the contact is in
+    if categories.len() > 0 {
+        lines.push(format!("CATEGORIES:{}", categories.iter().map(|c| escape_text(c)).collect::<Vec<_>>().join(",")));
+    }
+
+    lines.push("END:VCARD".to_string());
+
+    for l in lines.iter() {
+        out.push_str(&fold_line(l));
+    }
+}
+
+// contacts in a group, or all contacts if there's no group. None if the group
+// doesn't exist
+fn group_members(g: &Json) -> Vec<String> {
+    g.find("contactIds").and_then(|c| c.as_array()).map(|a| a.iter().filter_map(|id| id.as_string()).map(|id| id.to_string()).collect()).unwrap_or(vec!())
+}
+
+fn export_contacts(db: &Db, userid: i64, group_id: Option<&String>) -> Result<Option<String>,DbError> {
+    let (groups, contacts) = try!(db.transaction(|| {
+        Ok((
+            try!(db.get_records::<ContactGroup>(userid, None, None)),
+            try!(db.get_records::<Contact>(userid, None, None)),
+        ))
+    }));
+    let groups: Vec<Json> = groups.iter().map(|g| g.to_json()).collect();
+
+    let members: Option<Vec<String>> = match group_id {
+        Some(id) => match groups.iter().find(|g| str_prop(g, "id") == Some(&id[..])) {
+            Some(g) => Some(group_members(g)),
+            None    => return Ok(None),
+        },
+        None => None,
+    };
+
+    // group names become each member's categories
+    let mut categories: BTreeMap<String,Vec<String>> = BTreeMap::new();
+    for g in groups.iter() {
+        if let Some(name) = str_prop(g, "name") {
+            for id in group_members(g).into_iter() {
+                categories.entry(id).or_insert(vec!()).push(name.to_string());
+            }
+        }
+    }
+
+    let none = vec!();
+    let mut out = String::new();
+    for c in contacts.iter().map(|c| c.to_json()) {
+        let id = str_prop(&c, "id").unwrap_or("").to_string();
+        if let Some(ref m) = members {
+            if !m.contains(&id) { continue }
+        }
+        write_contact(&mut out, &c, categories.get(&id).unwrap_or(&none));
+    }
+
+    Ok(Some(out))
+}
+
+// GET /vcard/ downloads all contacts as a vCard file, or only those in a
+// group with ?group=<id>
+pub fn export_handler(path: &String, res: &mut Response, pool: &DbPool) -> StatusBody {
+    let group_id = query_param(path, "group");
+
+    let userid = 1; // XXX get userid from auth
+
+    let db = match pool.get() {
+        Ok(db) => db,
+        Err(e) => return StatusBody::new(StatusCode::ServiceUnavailable, Some(format!("{}", e).into_bytes())),
+    };
+
+    match export_contacts(&db, userid, group_id.as_ref()) {
+        Ok(Some(vcf)) => {
+            let headers = res.headers_mut();
+            headers.set(header::ContentType("text/vcard; charset=utf-8".parse().unwrap()));
+            headers.set(header::ContentLength(vcf.len() as u64));
+            headers.set_raw("Content-Disposition", vec!(b"attachment; filename=\"contacts.vcf\"".to_vec()));
+            StatusBody::new(StatusCode::Ok, Some(vcf.into_bytes()))
+        },
+        Ok(None) => StatusBody::new(StatusCode::NotFound, None),
+        Err(e)   => StatusBody::new(StatusCode::InternalServerError, Some(format!("{}", e).into_bytes())),
+    }
+}
+
+// split a structured value on unescaped ';', unescaping each component
+fn split_structured(s: &str) -> Vec<String> {
+    split_escaped(s, ';')
+}
+
+fn list_entry(typ: &str, value: String, default: bool) -> BTreeMap<String,Json> {
+    let mut o = BTreeMap::new();
+    o.insert("type".to_string(), typ.to_json());
+    o.insert("value".to_string(), value.to_json());
+    o.insert("isDefault".to_string(), default.to_json());
+    o
+}
+
+// build contact json from the properties of one vCard, and the names of the
+// groups it should be in
+fn parse_vcard(card: &Component) -> (Json, Vec<String>) {
+    let mut c: BTreeMap<String,Json> = BTreeMap::new();
+    let mut emails = vec!();
+    let mut phones = vec!();
+    let mut online = vec!();
+    let mut addresses = vec!();
+    let mut full_name = None;
+    let mut categories: Vec<String> = vec!();
+
+    for p in card.lines.iter() {
+        let types = p.types();
+        let pref = types.iter().any(|t| t == "PREF");
+        match p.name.as_ref() {
+            "FN" => full_name = Some(unescape_text(&p.value)),
+            "N"  => {
+                let n = split_structured(&p.value);
+                for &(i, prop) in [(0, "lastName"), (1, "firstName"), (3, "prefix"), (4, "suffix")].iter() {
+                    if let Some(v) = n.get(i) {
+                        c.insert(prop.to_string(), v.to_json());
+                    }
+                }
+            },
+            "NICKNAME" => { c.insert("nickname".to_string(), unescape_text(&p.value).to_json()); },
+            "ORG"      => {
+                let o = split_structured(&p.value);
+                if let Some(v) = o.get(0) { c.insert("company".to_string(), v.to_json()); }
+                if let Some(v) = o.get(1) { c.insert("department".to_string(), v.to_json()); }
+            },
+            "TITLE"         => { c.insert("jobTitle".to_string(), unescape_text(&p.value).to_json()); },
+            "NOTE"          => { c.insert("notes".to_string(), unescape_text(&p.value).to_json()); },
+            "BDAY"          => { c.insert("birthday".to_string(), vcard_date(&p.value).to_json()); },
+            "X-ANNIVERSARY" => { c.insert("anniversary".to_string(), vcard_date(&p.value).to_json()); },
+            "CATEGORIES"    => {
+                for cat in split_escaped(&p.value, ',').into_iter().map(|c| c.trim().to_string()) {
+                    if cat.len() > 0 && !categories.contains(&cat) {
+                        categories.push(cat);
+                    }
+                }
+            },
+            "EMAIL" => emails.push(Json::Object(list_entry(jmap_type(&EMAIL_TYPES, &types), unescape_text(&p.value), pref))),
+            "TEL"   => phones.push(Json::Object(list_entry(jmap_type(&PHONE_TYPES, &types), unescape_text(&p.value), pref))),
+            "URL"   => online.push(Json::Object(list_entry("uri", unescape_text(&p.value), pref))),
+            "ADR"   => {
+                let a = split_structured(&p.value);
+                let mut o = BTreeMap::new();
+                o.insert("type".to_string(), jmap_type(&ADDRESS_TYPES, &types).to_json());
+                // post office box and extended address go in with the street
+                let street = a.iter().take(3).filter(|s| s.len() > 0).cloned().collect::<Vec<_>>().join("\n");
+                o.insert("street".to_string(), street.to_json());
+                for &(i, prop) in [(3, "locality"), (4, "region"), (5, "postcode"), (6, "country")].iter() {
+                    o.insert(prop.to_string(), a.get(i).cloned().unwrap_or(String::new()).to_json());
+                }
+                o.insert("isDefault".to_string(), pref.to_json());
+                addresses.push(Json::Object(o));
+            },
+            _ => (),
+        }
+    }
+
+    // a card with only a formatted name gets it as the first name
+    if !c.contains_key("firstName") && !c.contains_key("lastName") {
+        if let Some(n) = full_name {
+            c.insert("firstName".to_string(), n.to_json());
+        }
+    }
+
+    if emails.len() > 0    { c.insert("emails".to_string(), Json::Array(emails)); }
+    if phones.len() > 0    { c.insert("phones".to_string(), Json::Array(phones)); }
+    if online.len() > 0    { c.insert("online".to_string(), Json::Array(online)); }
+    if addresses.len() > 0 { c.insert("addresses".to_string(), Json::Array(addresses)); }
+
+    (Json::Object(c), categories)
+}
+
+// "19700101" or "1970-01-01" => "1970-01-01"
+fn vcard_date(s: &str) -> String {
+    let d: String = s.chars().take_while(|c| *c != 'T').filter(|c| *c != '-').collect();
+    match d.len() {
+        8 => format!("{}-{}-{}", &d[0..4], &d[4..6], &d[6..8]),
+        _ => "0000-00-00".to_string(),
+    }
+}
+
+fn partial_group(name: Option<&str>, contact_ids: &Vec<String>) -> Result<<ContactGroup as Record>::Partial,DbError> {
+    let mut gj = BTreeMap::new();
+    if let Some(n) = name {
+        gj.insert("name".to_string(), n.to_json());
+    }
+    gj.insert("contactIds".to_string(), contact_ids.to_json());
+    Ok(try!(<ContactGroup as Record>::Partial::from_json(&Json::Object(gj))))
+}
+
+// create the contacts, then add them to the given group and to the groups
+// named by their categories. groups are matched by name, and created if
+// there's no group with that name yet
+fn import_contacts(db: &Db, userid: i64, create: &BTreeMap<String,<Contact as Record>::Partial>, categories: &BTreeMap<String,Vec<String>>, group_id: Option<&String>) -> Result<Option<(Vec<String>,BTreeMap<String,Json>)>,DbError> {
+    db.exclusive(|| {
+        let groups: Vec<Json> = try!(db.get_records::<ContactGroup>(userid, None, None)).iter().map(|g| g.to_json()).collect();
+        if let Some(id) = group_id {
+            if !groups.iter().any(|g| str_prop(g, "id") == Some(&id[..])) {
+                return Ok(None);
+            }
+        }
+
+        try!(db.next_state::<Contact>(userid));
+        let (created, not_created) = try!(db.create_records::<Contact>(userid, create));
+
+        let mut errors: BTreeMap<String,Json> = not_created.iter().map(|(i, e)| (i.clone(), e.to_json())).collect();
+
+        // new members for existing groups, by id, and for new groups, by name
+        let mut add: BTreeMap<String,Vec<String>> = BTreeMap::new();
+        let mut new_groups: BTreeMap<String,Vec<String>> = BTreeMap::new();
+
+        let mut ids = vec!();
+        for (i, pr) in created.iter() {
+            let id = match pr.to_json().find("id").and_then(|id| id.as_string()) {
+                Some(id) => id.to_string(),
+                None     => continue,
+            };
+            if let Some(gid) = group_id {
+                add.entry(gid.clone()).or_insert(vec!()).push(id.clone());
+            }
+            if let Some(cats) = categories.get(i) {
+                for name in cats.iter() {
+                    match groups.iter().find(|g| str_prop(g, "name") == Some(&name[..])).and_then(|g| str_prop(g, "id")) {
+                        Some(gid) => add.entry(gid.to_string()).or_insert(vec!()).push(id.clone()),
+                        None      => new_groups.entry(name.clone()).or_insert(vec!()).push(id.clone()),
+                    }
+                }
+            }
+            ids.push(id);
+        }
+
+        if add.len() == 0 && new_groups.len() == 0 {
+            return Ok(Some((ids, errors)));
+        }
+
+        try!(db.next_state::<ContactGroup>(userid));
+
+        let mut update = BTreeMap::new();
+        for (gid, new_ids) in add.iter() {
+            let mut contact_ids = groups.iter().find(|g| str_prop(g, "id") == Some(&gid[..])).map(|g| group_members(g)).unwrap_or(vec!());
+            for id in new_ids.iter() {
+                if !contact_ids.contains(id) {
+                    contact_ids.push(id.clone());
+                }
+            }
+            update.insert(gid.clone(), try!(partial_group(None, &contact_ids)));
+        }
+        let (_, not_updated) = try!(db.update_records::<ContactGroup>(userid, &update));
+
+        let mut create_groups = BTreeMap::new();
+        for (name, contact_ids) in new_groups.iter() {
+            create_groups.insert(name.clone(), try!(partial_group(Some(&name[..]), contact_ids)));
+        }
+        let (_, groups_not_created) = try!(db.create_records::<ContactGroup>(userid, &create_groups));
+
+        for (g, e) in not_updated.iter().chain(groups_not_created.iter()) {
+            errors.insert(format!("group {}", g), e.to_json());
+        }
+
+        Ok(Some((ids, errors)))
+    })
+}
+
+// POST /vcard/ creates a contact for each card in the vCard file in the
+// request body, adding them to the groups named in their CATEGORIES and to
+// a group with ?group=<id>. the response is
+//   { "created": [ ids ], "notCreated": { "<card index>": SetError } }
+// with groups that couldn't be changed as "group <id or name>" in notCreated
+pub fn import_handler(path: &String, mut req: Request, pool: &DbPool) -> StatusBody {
+    let group_id = query_param(path, "group");
+
+    let mut body = String::new();
+    if let Err(e) = req.read_to_string(&mut body) {
+        return StatusBody::new(StatusCode::BadRequest, Some(format!("{}", e).into_bytes()));
+    }
+
+    let mut create = BTreeMap::new();
+    let mut categories = BTreeMap::new();
+    for (i, card) in parse_components(&body).iter().filter(|c| c.name == "VCARD").enumerate() {
+        let (contact, cats) = parse_vcard(card);
+        categories.insert(i.to_string(), cats);
+        match <Contact as Record>::Partial::from_json(&contact) {
+            Ok(pr) => { create.insert(i.to_string(), pr); },
+            Err(e) => return StatusBody::new(StatusCode::BadRequest, Some(format!("card {}: {}", i, DbError::from(e)).into_bytes())),
+        }
+    }
+    if create.len() == 0 {
+        return StatusBody::new(StatusCode::BadRequest, Some("no vCards found".to_string().into_bytes()));
+    }
+
+    let userid = 1; // XXX get userid from auth
+
+    let db = match pool.get() {
+        Ok(db) => db,
+        Err(e) => return StatusBody::new(StatusCode::ServiceUnavailable, Some(format!("{}", e).into_bytes())),
+    };
+
+    match import_contacts(&db, userid, &create, &categories, group_id.as_ref()) {
+        Ok(Some((created, not_created))) => {
+            info!("imported {} contacts, {} failed", created.len(), not_created.len());
+            let mut out = BTreeMap::new();
+            out.insert("created".to_string(), created.to_json());
+            out.insert("notCreated".to_string(), not_created.to_json());
+            StatusBody::new(StatusCode::Ok, Some(out.to_json().to_string().into_bytes()))
+        },
+        Ok(None) => StatusBody::new(StatusCode::NotFound, None),
+        Err(e)   => StatusBody::new(StatusCode::InternalServerError, Some(format!("{}", e).into_bytes())),
+    }
+}